@@ -1,17 +1,23 @@
 use std::{
     collections::HashMap,
-    ops::{Add, AddAssign},
+    fmt::Display,
+    ops::{Add, AddAssign, Mul, Neg, Sub, SubAssign},
 };
 
-use crate::types::{Amount, Currency, Decimal};
+use crate::{
+    prices::PriceDb,
+    tolerance::is_within,
+    types::{Amount, Currency, Decimal},
+};
 
 /// Bag of currencies.
 ///
 /// The type is primarily used to accumulate many Amount types.
+///
+/// Two bags are equal if they hold the same non-zero amounts, see also Bag::eq_within.
 #[derive(Default, Debug, Clone)]
 pub struct Bag<D> {
-    // TODO: hide this
-    pub currencies: HashMap<Currency, D>,
+    currencies: HashMap<Currency, D>,
 }
 
 impl<D> Bag<D>
@@ -39,6 +45,142 @@ where
     pub fn commodities(&self) -> &HashMap<Currency, D> {
         &self.currencies
     }
+
+    /// Returns the amount of given currency, 0 if the bag does not hold it.
+    pub fn get(&self, currency: &Currency) -> D {
+        self.currencies.get(currency).cloned().unwrap_or_default()
+    }
+
+    /// Returns the amounts stored in the bag, ordered by currency.
+    pub fn iter(&self) -> impl Iterator<Item = Amount<D>> + '_ {
+        let mut currencies: Vec<&Currency> = self.currencies.keys().collect();
+        currencies.sort();
+        currencies.into_iter().map(|c| Amount {
+            value: self.currencies[c].clone(),
+            currency: c.clone(),
+        })
+    }
+
+    /// Returns true if, for every currency, the difference between the bags does not exceed the
+    /// tolerance returned for that currency.
+    ///
+    /// For example, `a.eq_within(&b, |c| tolerance.default_for(c))`.
+    pub fn eq_within(&self, other: &Bag<D>, tolerance: impl Fn(&Currency) -> D) -> bool {
+        (self.clone() - other)
+            .currencies
+            .iter()
+            .all(|(currency, value)| is_within(value, &tolerance(currency)))
+    }
+}
+
+impl<D> Bag<D>
+where
+    D: Decimal,
+{
+    /// Returns the value of the bag in the target currency, using the latest prices on or before
+    /// given date.
+    ///
+    /// Currencies without a known price are not converted and are returned separately.
+    pub fn convert(
+        &self,
+        prices: &PriceDb<D>,
+        target: &Currency,
+        date: chrono::NaiveDate,
+    ) -> Conversion<D> {
+        let mut res = Conversion {
+            value: Amount::new(0.into(), target.clone()),
+            unconverted: Bag::new(),
+        };
+        for amount in self.iter() {
+            match prices.rate(&amount.currency, target, date) {
+                Some(rate) => res.value.value += amount.value * rate,
+                None => res.unconverted += amount,
+            }
+        }
+        res.unconverted.trim();
+        res
+    }
+}
+
+/// Result of converting a bag to a single currency, see Bag::convert.
+#[derive(Debug, Clone)]
+pub struct Conversion<D> {
+    /// Total value of all converted amounts.
+    pub value: Amount<D>,
+    /// Amounts that could not be converted, as there is no price for their currency.
+    pub unconverted: Bag<D>,
+}
+
+impl<D> Conversion<D>
+where
+    D: Decimal,
+{
+    /// Returns true if all amounts were converted.
+    pub fn is_complete(&self) -> bool {
+        self.unconverted.is_zero()
+    }
+}
+
+impl<D> PartialEq for Bag<D>
+where
+    D: Decimal,
+{
+    fn eq(&self, other: &Self) -> bool {
+        (self.clone() - other).is_zero()
+    }
+}
+
+impl<D> Eq for Bag<D> where D: Decimal {}
+
+/// Writes the bag in the inventory syntax of beancount, e.g. `(10.00 CHF, -5 USD)`, skipping
+/// the currencies with value 0.
+impl<D> Display for Bag<D>
+where
+    D: Decimal,
+{
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let zero: D = Default::default();
+        write!(f, "(")?;
+        for (i, amount) in self.iter().filter(|a| a.value != zero).enumerate() {
+            if i > 0 {
+                write!(f, ", ")?;
+            }
+            write!(f, "{}", amount)?;
+        }
+        write!(f, ")")
+    }
+}
+
+impl<D> IntoIterator for Bag<D>
+where
+    D: Decimal,
+{
+    type Item = Amount<D>;
+    type IntoIter = std::vec::IntoIter<Amount<D>>;
+
+    /// Returns the amounts stored in the bag, ordered by currency.
+    fn into_iter(self) -> Self::IntoIter {
+        let mut amounts: Vec<Amount<D>> = self
+            .currencies
+            .into_iter()
+            .map(|(currency, value)| Amount { value, currency })
+            .collect();
+        amounts.sort_by(|a, b| a.currency.cmp(&b.currency));
+        amounts.into_iter()
+    }
+}
+
+impl<D> FromIterator<Amount<D>> for Bag<D>
+where
+    D: Decimal,
+{
+    fn from_iter<T: IntoIterator<Item = Amount<D>>>(iter: T) -> Self {
+        let mut bag = Bag::new();
+        for amount in iter {
+            bag += amount;
+        }
+        bag
+    }
 }
 
 impl<D> AddAssign<Amount<D>> for Bag<D>
@@ -87,6 +229,95 @@ where
     }
 }
 
+impl<D> SubAssign<Amount<D>> for Bag<D>
+where
+    D: Decimal,
+{
+    fn sub_assign(&mut self, rhs: Amount<D>) {
+        *self += -rhs;
+    }
+}
+
+impl<D> SubAssign<Bag<D>> for Bag<D>
+where
+    D: Decimal,
+{
+    fn sub_assign(&mut self, rhs: Bag<D>) {
+        *self += -rhs;
+    }
+}
+
+impl<D> SubAssign<&Bag<D>> for Bag<D>
+where
+    D: Decimal,
+{
+    fn sub_assign(&mut self, rhs: &Bag<D>) {
+        for (commodity, amount) in &rhs.currencies {
+            let entry = self.currencies.entry(commodity.clone()).or_default();
+            *entry = entry.clone() - amount.clone();
+        }
+    }
+}
+
+impl<D> Sub<Bag<D>> for Bag<D>
+where
+    D: Decimal,
+{
+    type Output = Bag<D>;
+
+    fn sub(self, rhs: Bag<D>) -> Bag<D> {
+        self - &rhs
+    }
+}
+
+impl<D> Sub<&Bag<D>> for Bag<D>
+where
+    D: Decimal,
+{
+    type Output = Bag<D>;
+
+    fn sub(self, rhs: &Bag<D>) -> Bag<D> {
+        let mut res = self;
+        res -= rhs;
+        res
+    }
+}
+
+impl<D> Neg for Bag<D>
+where
+    D: Decimal,
+{
+    type Output = Bag<D>;
+
+    fn neg(self) -> Bag<D> {
+        Bag {
+            currencies: self
+                .currencies
+                .into_iter()
+                .map(|(commodity, amount)| (commodity, -amount))
+                .collect(),
+        }
+    }
+}
+
+/// Multiplies every amount in the bag by a number.
+impl<D> Mul<D> for Bag<D>
+where
+    D: Decimal,
+{
+    type Output = Bag<D>;
+
+    fn mul(self, rhs: D) -> Bag<D> {
+        Bag {
+            currencies: self
+                .currencies
+                .into_iter()
+                .map(|(commodity, amount)| (commodity, amount * rhs.clone()))
+                .collect(),
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -136,10 +367,8 @@ mod tests {
             value: 2.into(),
             currency: "USD".into(),
         };
-        let expected: HashMap<Currency, rust_decimal::Decimal> = HashMap::from([
-            (Currency("CHF".into()), 1.into()),
-            (Currency("USD".into()), 2.into()),
-        ]);
+        let expected: HashMap<Currency, rust_decimal::Decimal> =
+            HashMap::from([("CHF".into(), 1.into()), ("USD".into(), 2.into())]);
         assert_eq!(bag.currencies, expected);
     }
 
@@ -156,10 +385,8 @@ mod tests {
             currency: "USD".into(),
         };
         let bag = bag1 + bag2;
-        let expected: HashMap<Currency, rust_decimal::Decimal> = HashMap::from([
-            (Currency("CHF".into()), 1.into()),
-            (Currency("USD".into()), 2.into()),
-        ]);
+        let expected: HashMap<Currency, rust_decimal::Decimal> =
+            HashMap::from([("CHF".into(), 1.into()), ("USD".into(), 2.into())]);
         assert_eq!(bag.currencies, expected);
     }
 
@@ -176,10 +403,70 @@ mod tests {
             currency: "USD".into(),
         };
         bag1 += bag2;
-        let expected: HashMap<Currency, rust_decimal::Decimal> = HashMap::from([
-            (Currency("CHF".into()), 1.into()),
-            (Currency("USD".into()), 2.into()),
-        ]);
+        let expected: HashMap<Currency, rust_decimal::Decimal> =
+            HashMap::from([("CHF".into(), 1.into()), ("USD".into(), 2.into())]);
         assert_eq!(bag1.currencies, expected);
     }
+
+    fn bag(amounts: &[(&str, &str)]) -> Bag<rust_decimal::Decimal> {
+        amounts
+            .iter()
+            .map(|(value, currency)| Amount::new(value.parse().unwrap(), *currency))
+            .collect()
+    }
+
+    #[test]
+    fn bag_supports_sub_neg_and_mul() {
+        let a = bag(&[("10", "CHF"), ("2", "USD")]);
+        let b = bag(&[("3", "CHF"), ("2", "USD")]);
+        assert_eq!(a.clone() - b.clone(), bag(&[("7", "CHF")]));
+        assert_eq!(-a.clone(), bag(&[("-10", "CHF"), ("-2", "USD")]));
+        assert_eq!(a * 3.into(), bag(&[("30", "CHF"), ("6", "USD")]));
+        let mut c = b;
+        c -= Amount::new(3.into(), "CHF");
+        assert_eq!(c, bag(&[("2", "USD")]));
+    }
+
+    #[test]
+    fn bag_iterates_and_displays_in_currency_order() {
+        let b = bag(&[("2", "USD"), ("10.00", "CHF"), ("0", "EUR")]);
+        let currencies: Vec<String> = b.iter().map(|a| a.currency.to_string()).collect();
+        assert_eq!(currencies, ["CHF", "EUR", "USD"]);
+        assert_eq!(b.to_string(), "(10.00 CHF, 2 USD)");
+        assert_eq!(Bag::<rust_decimal::Decimal>::new().to_string(), "()");
+    }
+
+    #[test]
+    fn bag_converts_to_target_currency() {
+        let mut prices = PriceDb::new();
+        let date = chrono::NaiveDate::from_ymd_opt(2023, 1, 1).unwrap();
+        prices.insert(
+            date,
+            "USD".into(),
+            &Amount::new("0.9".parse().unwrap(), "CHF"),
+        );
+        let b = bag(&[("10", "CHF"), ("20", "USD"), ("3", "HOOL")]);
+        let c = b.convert(&prices, &"CHF".into(), date);
+        assert_eq!(c.value, Amount::new("28.0".parse().unwrap(), "CHF"));
+        assert_eq!(c.unconverted, bag(&[("3", "HOOL")]));
+        assert!(!c.is_complete());
+        let c = b.convert(&prices, &"CHF".into(), date.pred_opt().unwrap());
+        assert_eq!(c.value, Amount::new(10.into(), "CHF"));
+    }
+
+    #[test]
+    fn bag_equality_within_tolerance() {
+        let a = bag(&[("10.00", "CHF"), ("2", "USD")]);
+        let b = bag(&[("10.004", "CHF"), ("2", "USD")]);
+        let tolerance = |c: &Currency| {
+            if c.as_str() == "CHF" {
+                "0.005".parse().unwrap()
+            } else {
+                0.into()
+            }
+        };
+        assert!(a.eq_within(&b, tolerance));
+        assert!(!a.eq_within(&bag(&[("10.01", "CHF"), ("2", "USD")]), tolerance));
+        assert!(!a.eq_within(&bag(&[("10.00", "CHF")]), tolerance));
+    }
 }
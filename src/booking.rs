@@ -0,0 +1,573 @@
+use crate::{
+    bag::Bag,
+    inventory::{Booking, Inventory, Lot, Position},
+    tolerance::{is_within, Tolerance},
+    types::*,
+    Error, Location, Result,
+};
+use std::{collections::HashMap, path::PathBuf};
+
+/// Result of booking all transactions of a ledger.
+#[derive(Debug, Default)]
+pub struct Booked<D: Decimal> {
+    /// Inventories of all accounts, after all transactions are booked.
+    pub inventories: HashMap<Account, Inventory<D>>,
+    /// Transactions that could not be booked, with the location of the directive. They do not
+    /// affect the inventories.
+    pub errors: Vec<Error<D>>,
+}
+
+/// Books all transactions of the ledger in the canonical order.
+///
+/// Reductions of positions held at cost are matched against the lots of the account using the
+/// booking method of its open directive, or the `booking_method` option of the ledger (STRICT by
+/// default). The ledger itself is not modified.
+pub fn book<D: Decimal>(ledger: &Ledger<D>) -> Booked<D> {
+    let mut booked = Booked {
+        inventories: HashMap::new(),
+        errors: Vec::new(),
+    };
+    let default_booking = ledger
+        .files
+        .iter()
+        .flat_map(|(_, file)| &file.options)
+        .find(|(name, _)| name == "booking_method")
+        .map(|(_, value)| value.as_str());
+    let default_booking = match default_booking.map(|b| b.parse::<Booking>()) {
+        Some(Ok(b)) => b,
+        Some(Err(_)) => {
+            booked.errors.push(
+                Error::InvalidOption {
+                    name: "booking_method".into(),
+                    value: default_booking.unwrap_or_default().into(),
+                }
+                .at(options_location(ledger)),
+            );
+            Booking::default()
+        }
+        None => Booking::default(),
+    };
+    let tolerance = match Tolerance::from_ledger(ledger) {
+        Ok(t) => t,
+        Err(err) => {
+            booked.errors.push(err.at(options_location(ledger)));
+            Tolerance::default()
+        }
+    };
+    let mut methods: HashMap<Account, Booking> = HashMap::new();
+    let mut transactions: Vec<(&PathBuf, &Directive<D>, &Transaction<D>)> = Vec::new();
+    for (path, d) in ledger.directives() {
+        match &d.content {
+            DirectiveContent::Open(open) => {
+                let method = match open.booking_method.as_deref() {
+                    Some(method) => match method.parse::<Booking>() {
+                        Ok(method) => method,
+                        Err(_) => {
+                            booked.errors.push(
+                                Error::UnknownBookingMethod(method.to_string())
+                                    .at(d.location(path)),
+                            );
+                            default_booking
+                        }
+                    },
+                    None => default_booking,
+                };
+                methods.insert(open.account.clone(), method);
+            }
+            DirectiveContent::Transaction(t) => transactions.push((path, d, t)),
+            _ => (),
+        }
+    }
+
+    for (path, d, t) in transactions {
+        let changes = match book_transaction(d.date, t, &booked.inventories, &tolerance, |a| {
+            methods.get(a).copied().unwrap_or(default_booking)
+        }) {
+            Ok(changes) => changes,
+            Err(err) => {
+                booked.errors.push(err.at(d.location(path)));
+                continue;
+            }
+        };
+        for (account, position, average) in changes {
+            let inventory = booked.inventories.entry(account).or_default();
+            if average {
+                inventory.average(&position.units.currency);
+            }
+            inventory.add(position.units, position.lot);
+        }
+    }
+    booked
+}
+
+/// Location reported for errors in the options, which are not tied to a single directive.
+fn options_location<D>(ledger: &Ledger<D>) -> Location {
+    Location {
+        path: ledger
+            .files
+            .first()
+            .map(|(p, _)| p.clone())
+            .unwrap_or_default(),
+        line: None,
+    }
+}
+
+/// Books a single transaction against the inventories.
+///
+/// Returns the positions to add to the inventory of each account, together with a flag whether
+/// the lots of the inventory should be averaged first (for reductions under the AVERAGE booking
+/// method or with the `{*}` merge spec).
+fn book_transaction<D: Decimal>(
+    date: chrono::NaiveDate,
+    t: &Transaction<D>,
+    inventories: &HashMap<Account, Inventory<D>>,
+    tolerance: &Tolerance<D>,
+    booking: impl Fn(&Account) -> Booking,
+) -> Result<Vec<(Account, Position<D>, bool)>, D> {
+    let empty = Inventory::new();
+    let mut postings = t.postings.clone();
+    clear_autocomputed(&mut postings);
+
+    // Replace the reductions with one posting per matched lot.
+    let mut booked: Vec<Posting<D>> = Vec::new();
+    // The matched lot of every reduction, and whether the inventory is averaged.
+    let mut reductions: Vec<Option<(Lot<D>, bool)>> = Vec::new();
+    for posting in postings {
+        let (units, cost) = match (&posting.amount, &posting.cost) {
+            (Some(units), Some(cost)) => (units, cost),
+            _ => {
+                booked.push(posting);
+                reductions.push(None);
+                continue;
+            }
+        };
+        let method = booking(&posting.account);
+        let inventory = inventories.get(&posting.account).unwrap_or(&empty);
+        if method == Booking::None || !inventory.is_reduction(units) {
+            booked.push(posting);
+            reductions.push(None);
+            continue;
+        }
+        let matched = inventory
+            .match_reduction(units, cost, method)
+            .map_err(|err| err.posting(posting.account.clone()))?;
+        for m in matched {
+            let lot = m.lot.clone().expect("reductions always match lots");
+            booked.push(Posting {
+                amount: Some(m.units),
+                cost: Some(Cost {
+                    number_per: Some(lot.cost.value.clone()),
+                    currency: Some(lot.cost.currency.clone()),
+                    date: Some(lot.date),
+                    label: lot.label.clone(),
+                    ..Default::default()
+                }),
+                ..posting.clone()
+            });
+            reductions.push(Some((lot, method == Booking::Average || cost.merge)));
+        }
+    }
+
+    let interpolation = interpolate(&booked, tolerance)?;
+    for (i, cost) in interpolation.costs {
+        booked[i].cost = Some(cost);
+    }
+    if let Some((i, amounts)) = &interpolation.auto {
+        let split = std::iter::repeat_n(None, amounts.len());
+        reductions.splice(*i..*i + 1, split);
+    }
+    fill_auto_posting(&mut booked, interpolation.auto);
+
+    let mut changes = Vec::new();
+    for (posting, reduction) in booked.into_iter().zip(reductions) {
+        let units = match posting.amount {
+            Some(units) => units,
+            None => continue,
+        };
+        let average = reduction.as_ref().is_some_and(|(_, average)| *average);
+        let lot = match (reduction, &posting.cost) {
+            (Some((lot, _)), _) => Some(lot),
+            (None, Some(cost)) => {
+                let unit_cost = cost
+                    .unit_cost(&units.value)
+                    .ok_or_else(|| Error::UnknownCost {
+                        account: posting.account.clone(),
+                    })?;
+                Some(Lot {
+                    cost: unit_cost,
+                    date: cost.date.unwrap_or(date),
+                    label: cost.label.clone(),
+                })
+            }
+            (None, None) => None,
+        };
+        changes.push((posting.account, Position { units, lot }, average));
+    }
+    Ok(changes)
+}
+
+/// The result of interpolating the postings of a single transaction.
+///
+/// It only describes what is missing, so that the caller can decide which parts to fill in.
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) struct Interpolation<D> {
+    /// Index of the posting without amount, together with the amounts it should be split into
+    /// (one per residual currency).
+    pub auto: Option<(usize, Vec<Amount<D>>)>,
+    /// Costs inferred for postings with incomplete cost spec.
+    pub costs: Vec<(usize, Cost<D>)>,
+}
+
+/// Interpolates the missing numbers of the postings, the way beancount does it.
+///
+/// At most one posting can be missing its amount; it receives one amount per currency that does
+/// not balance. A posting with incomplete cost (like `{}` or `{USD}`) gets its per unit cost
+/// inferred from the other legs, if it is the only unknown of the transaction. The residual of
+/// each currency is compared against the tolerance inferred from the precision of the amounts.
+///
+/// Prices are never inferred: postings with a price without number (like `10 HOOL @ USD`) are
+/// rejected when parsing, see Error::MissingPriceNumber.
+pub(crate) fn interpolate<D: Decimal>(
+    postings: &[Posting<D>],
+    tolerance: &Tolerance<D>,
+) -> Result<Interpolation<D>, D> {
+    let tolerances = tolerance.infer(postings);
+    let mut residual: Bag<D> = Bag::new();
+    let mut auto: Option<usize> = None;
+    let mut unknown_cost: Option<usize> = None;
+    for (i, posting) in postings.iter().enumerate() {
+        let units = match &posting.amount {
+            Some(units) => units,
+            None => {
+                if let Some(j) = auto {
+                    return Err(Error::ManyAutoPostings {
+                        first: postings[j].account.clone(),
+                        second: posting.account.clone(),
+                    });
+                }
+                auto = Some(i);
+                continue;
+            }
+        };
+        if let Some(cost) = &posting.cost {
+            if !cost.is_complete() {
+                if let Some(j) = unknown_cost {
+                    return Err(Error::ManyUnknownCosts {
+                        first: postings[j].account.clone(),
+                        second: posting.account.clone(),
+                    });
+                }
+                unknown_cost = Some(i);
+                continue;
+            }
+        }
+        if let Some(weight) = posting_amount_to_balance(posting) {
+            residual += weight;
+        } else {
+            residual += units.clone();
+        }
+    }
+    let residual: Vec<Amount<D>> = residual
+        .into_iter()
+        .filter(|a| {
+            let t = tolerances
+                .get(&a.currency)
+                .cloned()
+                .unwrap_or_else(|| tolerance.default_for(&a.currency));
+            !is_within(&a.value, &t)
+        })
+        .collect();
+
+    let mut result = Interpolation {
+        auto: None,
+        costs: Vec::new(),
+    };
+    match (auto, unknown_cost) {
+        (Some(a), Some(c)) => {
+            return Err(Error::UnknownCostWithAutoPosting {
+                account: postings[c].account.clone(),
+                auto: postings[a].account.clone(),
+            })
+        }
+        (Some(a), None) => {
+            if residual.is_empty() {
+                return Err(Error::NothingToInterpolate {
+                    account: postings[a].account.clone(),
+                });
+            }
+            result.auto = Some((a, residual.into_iter().map(|a| -a).collect()));
+        }
+        (None, Some(c)) => {
+            let posting = &postings[c];
+            let cost = posting.cost.clone().unwrap_or_default();
+            // The amount is always set here, as postings without it are handled above.
+            let units = posting.amount.clone().unwrap();
+            let r = match residual.as_slice() {
+                [r] => r,
+                [] => {
+                    return Err(Error::UnknownCostBalanced {
+                        account: posting.account.clone(),
+                    })
+                }
+                _ => {
+                    return Err(Error::UnknownCostManyCurrencies {
+                        account: posting.account.clone(),
+                        residual,
+                    })
+                }
+            };
+            if let Some(currency) = &cost.currency {
+                if *currency != r.currency {
+                    return Err(Error::UnknownCostCurrency {
+                        account: posting.account.clone(),
+                        cost: currency.clone(),
+                        residual: r.currency.clone(),
+                    });
+                }
+            }
+            if units.value == 0.into() {
+                return Err(Error::UnknownCostWithoutUnits {
+                    account: posting.account.clone(),
+                });
+            }
+            let mut cost = cost;
+            if cost.number_per.is_none() && cost.number_total.is_none() {
+                cost.number_per = Some(-r.value.clone() / units.value.clone());
+            }
+            cost.currency = Some(r.currency.clone());
+            result.costs.push((c, cost));
+        }
+        (None, None) => {
+            if !residual.is_empty() {
+                return Err(Error::Unbalanced { residual });
+            }
+        }
+    }
+    Ok(result)
+}
+
+/// Replaces the posting without amount with one autocomputed posting per given amount.
+pub(crate) fn fill_auto_posting<D: Decimal>(
+    postings: &mut Vec<Posting<D>>,
+    auto: Option<(usize, Vec<Amount<D>>)>,
+) {
+    let (i, amounts) = match auto {
+        Some(auto) => auto,
+        None => return,
+    };
+    let template = postings[i].clone();
+    let split = amounts.into_iter().map(|amount| Posting {
+        amount: Some(amount),
+        autocomputed: true,
+        ..template.clone()
+    });
+    postings.splice(i..i + 1, split);
+}
+
+/// Reverts the results of interpolation, turning the autocomputed postings back into a single
+/// posting without amount.
+pub(crate) fn clear_autocomputed<D>(postings: &mut Vec<Posting<D>>) {
+    postings.dedup_by(|b, a| a.autocomputed && b.autocomputed && a.account == b.account);
+    for posting in postings {
+        if posting.autocomputed {
+            posting.amount = None;
+            posting.autocomputed = false;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+    use rust_decimal::Decimal;
+
+    fn postings(input: &str) -> Vec<Posting<Decimal>> {
+        let file = crate::parse(input).unwrap();
+        match file.directives.into_iter().next().unwrap().content {
+            DirectiveContent::Transaction(mut t) => {
+                t.clear_autocomputed();
+                t.postings
+            }
+            _ => panic!("not a transaction"),
+        }
+    }
+
+    fn amount(value: &str, currency: &str) -> Amount<Decimal> {
+        Amount {
+            value: value.parse().unwrap(),
+            currency: currency.into(),
+        }
+    }
+
+    #[test]
+    fn splits_posting_without_amount_per_currency() {
+        let p = postings(
+            r#"2020-01-01 * "Transfer"
+  Assets:Cash -10.00 CHF
+  Assets:Wallet -5.00 USD
+  Assets:Bank
+"#,
+        );
+        let i = interpolate(&p, &Tolerance::default()).unwrap();
+        assert_eq!(
+            i.auto,
+            Some((2, vec![amount("10.00", "CHF"), amount("5.00", "USD")]))
+        );
+    }
+
+    #[test]
+    fn infers_cost_number() {
+        let p = postings(
+            r#"2020-01-01 * "Buy"
+  Assets:Stock 10 HOOL {}
+  Assets:Cash -1500.00 USD
+"#,
+        );
+        let i = interpolate(&p, &Tolerance::default()).unwrap();
+        assert_eq!(i.costs, vec![(0, Cost::per_unit(amount("150", "USD")))]);
+    }
+
+    #[test]
+    fn accepts_residual_within_tolerance() {
+        let p = postings(
+            r#"2020-01-01 * "Buy"
+  Assets:Stock 3 HOOL {33.333 USD}
+  Assets:Cash -100.00 USD
+"#,
+        );
+        assert!(interpolate(&p, &Tolerance::default()).is_ok());
+    }
+
+    #[test]
+    fn rejects_residual_above_tolerance() {
+        let p = postings(
+            r#"2020-01-01 * "Buy"
+  Assets:Stock 3 HOOL {33.33 USD}
+  Assets:Cash -100.00 USD
+"#,
+        );
+        let err = interpolate(&p, &Tolerance::default()).unwrap_err();
+        assert_eq!(err.to_string(), "transaction does not balance: -0.01 USD");
+    }
+
+    fn ledger(input: &str) -> Ledger<Decimal> {
+        Ledger {
+            files: vec![("main.beancount".into(), crate::parse(input).unwrap())],
+        }
+    }
+
+    #[test]
+    fn book_reduces_lots_using_account_booking_method() {
+        let l = ledger(
+            r#"2020-01-01 open Assets:Stock HOOL "FIFO"
+2020-01-01 open Assets:Cash
+2020-01-01 open Income:Gains
+2020-01-02 * "Buy"
+  Assets:Stock 10 HOOL {100 USD}
+  Assets:Cash -1000 USD
+2020-01-03 * "Buy"
+  Assets:Stock 10 HOOL {120 USD}
+  Assets:Cash -1200 USD
+2020-01-04 * "Sell"
+  Assets:Stock -15 HOOL {}
+  Assets:Cash 2250 USD
+  Income:Gains
+"#,
+        );
+        let booked = book(&l);
+        assert_eq!(booked.errors.len(), 0, "{:?}", booked.errors);
+        let stock = &booked.inventories[&"Assets:Stock".into()];
+        assert_eq!(stock.positions().len(), 1);
+        assert_eq!(stock.positions()[0].units, amount("5", "HOOL"));
+        let gains = booked.inventories[&"Income:Gains".into()].units();
+        assert_eq!(gains.commodities()[&"USD".into()], "-650".parse().unwrap());
+    }
+
+    #[test]
+    fn book_merges_lots_of_fifo_account() {
+        // The parser does not read `{*}`, so the merge is set on the parsed `{}` spec.
+        let merged = |sold: &str| {
+            let mut l = ledger(&format!(
+                r#"2020-01-01 open Assets:Stock HOOL "FIFO"
+2020-01-02 * "Buy"
+  Assets:Stock 10 HOOL {{100 USD}}
+  Assets:Cash -1000 USD
+2020-01-03 * "Buy"
+  Assets:Stock 10 HOOL {{120 USD}}
+  Assets:Cash -1200 USD
+2020-01-04 * "Sell"
+  Assets:Stock -{} HOOL {{}}
+  Assets:Cash
+"#,
+                sold
+            ));
+            if let DirectiveContent::Transaction(t) = &mut l.files[0].1.directives[3].content {
+                t.postings[0].cost = Some(Cost {
+                    merge: true,
+                    ..Default::default()
+                });
+            }
+            l
+        };
+        let booked = book(&merged("5"));
+        assert_eq!(booked.errors.len(), 0, "{:?}", booked.errors);
+        let stock = &booked.inventories[&"Assets:Stock".into()];
+        assert_eq!(stock.positions().len(), 1);
+        assert_eq!(stock.positions()[0].units, amount("15", "HOOL"));
+        assert_eq!(
+            stock.positions()[0].lot.as_ref().map(|l| &l.cost),
+            Some(&amount("110", "USD"))
+        );
+
+        let booked = book(&merged("25"));
+        assert_eq!(
+            booked.errors[0].to_string(),
+            "main.beancount:8: posting to Assets:Stock: not enough units of HOOL matching {*}: requested 25, available 20"
+        );
+    }
+
+    #[test]
+    fn book_reports_ambiguous_reduction() {
+        let l = ledger(
+            r#"2020-01-02 * "Buy"
+  Assets:Stock 10 HOOL {100 USD}
+  Assets:Cash -1000 USD
+2020-01-03 * "Buy"
+  Assets:Stock 10 HOOL {120 USD}
+  Assets:Cash -1200 USD
+2020-01-04 * "Sell"
+  Assets:Stock -5 HOOL {}
+  Assets:Cash 600 USD
+"#,
+        );
+        let booked = book(&l);
+        assert_eq!(booked.errors.len(), 1);
+        assert_eq!(
+            booked.errors[0].location(),
+            Some(&Location {
+                path: "main.beancount".into(),
+                line: Some(7)
+            })
+        );
+        assert_eq!(
+            booked.errors[0].to_string(),
+            "main.beancount:7: posting to Assets:Stock: ambiguous reduction of -5 HOOL matching {}, 2 lots match: {100 USD, 2020-01-02}, {120 USD, 2020-01-03}"
+        );
+    }
+
+    #[test]
+    fn reports_posting_that_cannot_be_resolved() {
+        let p = postings(
+            r#"2020-01-01 * "Buy"
+  Assets:Stock 10 HOOL {}
+  Assets:Cash
+"#,
+        );
+        let err = interpolate(&p, &Tolerance::default()).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "cannot infer the cost of posting to Assets:Stock, as the posting to Assets:Cash has no amount"
+        );
+    }
+}
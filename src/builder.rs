@@ -0,0 +1,166 @@
+//! Builders for constructing transactions and postings, e.g. in importers:
+//!
+//! ```
+//! # use beanru::types::*;
+//! let date = chrono::NaiveDate::from_ymd_opt(2023, 1, 2).unwrap();
+//! let directive: Directive<rust_decimal::Decimal> = Transaction::builder(date)
+//!     .narration("Coffee")
+//!     .link("id-1234")
+//!     .posting("Expenses:Food", Amount::new(3.into(), "CHF"))
+//!     .auto_posting("Assets:Cash")
+//!     .build();
+//! assert_eq!(directive.content.transaction_opt().map(|t| t.balanced), Some(true));
+//! ```
+use crate::types::*;
+use indexmap::{IndexMap, IndexSet};
+
+/// Builder of a transaction directive, see Transaction::builder.
+#[derive(Debug)]
+pub struct TransactionBuilder<D> {
+    date: chrono::NaiveDate,
+    metadata: IndexMap<String, MetadataValue<D>>,
+    transaction: Transaction<D>,
+}
+
+impl<D> TransactionBuilder<D>
+where
+    D: Decimal,
+{
+    pub(crate) fn new(date: chrono::NaiveDate) -> Self {
+        Self {
+            date,
+            metadata: IndexMap::new(),
+            transaction: Transaction {
+                flag: None,
+                payee: None,
+                narration: None,
+                tags: IndexSet::new(),
+                links: IndexSet::new(),
+                postings: Vec::new(),
+                balanced: false,
+            },
+        }
+    }
+
+    /// Sets the flag of the transaction (written as `*` if not set).
+    pub fn flag(mut self, flag: char) -> Self {
+        self.transaction.flag = Some(flag);
+        self
+    }
+
+    pub fn payee(mut self, payee: impl Into<String>) -> Self {
+        self.transaction.payee = Some(payee.into());
+        self
+    }
+
+    pub fn narration(mut self, narration: impl Into<String>) -> Self {
+        self.transaction.narration = Some(narration.into());
+        self
+    }
+
+    /// Adds a tag (without the leading `#`).
+    pub fn tag(mut self, tag: impl Into<String>) -> Self {
+        self.transaction.tags.insert(tag.into());
+        self
+    }
+
+    /// Adds a link (without the leading `^`).
+    pub fn link(mut self, link: impl Into<String>) -> Self {
+        self.transaction.links.insert(link.into());
+        self
+    }
+
+    /// Adds metadata to the transaction.
+    pub fn meta(mut self, key: impl Into<String>, value: MetadataValue<D>) -> Self {
+        self.metadata.insert(key.into(), value);
+        self
+    }
+
+    /// Adds a posting of given units to the account.
+    pub fn posting(self, account: impl Into<Account>, amount: Amount<D>) -> Self {
+        self.with_posting(Posting::builder(account).amount(amount).build())
+    }
+
+    /// Adds a posting without amount, which is interpolated when the transaction is built.
+    pub fn auto_posting(self, account: impl Into<Account>) -> Self {
+        self.with_posting(Posting::builder(account).build())
+    }
+
+    /// Adds a posting built separately, e.g. with cost or price.
+    pub fn with_posting(mut self, posting: Posting<D>) -> Self {
+        self.transaction.postings.push(posting);
+        self
+    }
+
+    /// Returns the transaction directive.
+    ///
+    /// The transaction is booked like a parsed one, so the `balanced` field tells whether it
+    /// balances.
+    pub fn build(self) -> Directive<D> {
+        let mut transaction = self.transaction;
+        transaction.balanced = transaction.book().is_ok();
+        Directive {
+            metadata: self.metadata,
+            ..Directive::new(self.date, DirectiveContent::Transaction(transaction))
+        }
+    }
+}
+
+/// Builder of a posting, see Posting::builder.
+#[derive(Debug)]
+pub struct PostingBuilder<D> {
+    posting: Posting<D>,
+}
+
+impl<D> PostingBuilder<D> {
+    pub(crate) fn new(account: Account) -> Self {
+        Self {
+            posting: Posting {
+                flag: None,
+                account,
+                amount: None,
+                cost: None,
+                price: None,
+                metadata: IndexMap::new(),
+                autocomputed: false,
+            },
+        }
+    }
+
+    pub fn flag(mut self, flag: char) -> Self {
+        self.posting.flag = Some(flag);
+        self
+    }
+
+    /// Sets the units of the posting.
+    pub fn amount(mut self, amount: Amount<D>) -> Self {
+        self.posting.amount = Some(amount);
+        self
+    }
+
+    pub fn cost(mut self, cost: Cost<D>) -> Self {
+        self.posting.cost = Some(cost);
+        self
+    }
+
+    /// Sets the price of a single unit (`@`).
+    pub fn unit_price(mut self, price: Amount<D>) -> Self {
+        self.posting.price = Some(PostingPrice::Unit(price));
+        self
+    }
+
+    /// Sets the price of all units (`@@`).
+    pub fn total_price(mut self, price: Amount<D>) -> Self {
+        self.posting.price = Some(PostingPrice::Total(price));
+        self
+    }
+
+    pub fn meta(mut self, key: impl Into<String>, value: MetadataValue<D>) -> Self {
+        self.posting.metadata.insert(key.into(), value);
+        self
+    }
+
+    pub fn build(self) -> Posting<D> {
+        self.posting
+    }
+}
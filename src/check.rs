@@ -1,22 +1,590 @@
-use crate::types::*;
+use crate::{
+    bag::Bag,
+    diagnostic::Diagnostic,
+    lint::LintConfig,
+    tolerance::{is_within, Tolerance},
+    types::*,
+    Error, Location,
+};
+use std::{
+    collections::{HashMap, HashSet},
+    path::Path,
+};
 
-/// Checks if given beancount file is correct.
+/// Options of the checks that are not done by default, see check_with_options.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct CheckOptions {
+    /// If set, commodities used without a `commodity` directive are reported.
+    pub undeclared_commodities: bool,
+    /// Lint rules enforcing the conventions of the ledger, see the lint module.
+    pub lints: LintConfig,
+}
+
+/// Checks if given beancount file is correct and returns the problems found, ordered by their
+/// location.
+///
+/// Every transaction is booked against the inventories of its accounts, which verifies that it
+/// balances and that its reductions match the held lots. The accounts have to be open whenever
+/// they are used, see check_accounts, and the balance directives have to match the postings, see
+/// check_balances. The currencies have to be allowed by the open directives, see
+/// check_currencies. Finally, the transactions are linted with the default lint rules.
+///
+/// The documents are not checked, as it needs the file system, see
+/// documents::check_with_documents.
+pub fn check<D: Decimal>(ledger: &Ledger<D>) -> Vec<Diagnostic> {
+    check_with_options(ledger, &Default::default())
+}
+
+/// Same as check, but also does the optional checks enabled in the options.
+pub fn check_with_options<D: Decimal>(
+    ledger: &Ledger<D>,
+    options: &CheckOptions,
+) -> Vec<Diagnostic> {
+    let mut diagnostics: Vec<Diagnostic> = crate::booking::book(ledger)
+        .errors
+        .into_iter()
+        .chain(check_accounts(ledger))
+        .chain(check_balances(ledger))
+        .chain(check_currencies(ledger, options))
+        .map(Diagnostic::from)
+        .chain(crate::lint::lint(ledger, &options.lints))
+        .collect();
+    sort_by_location(ledger, &mut diagnostics);
+    diagnostics
+}
+
+/// Sorts the diagnostics by the order of the files in the ledger and then by line. Diagnostics
+/// without location go first.
+pub(crate) fn sort_by_location<D>(ledger: &Ledger<D>, diagnostics: &mut [Diagnostic]) {
+    diagnostics.sort_by_key(|d| {
+        d.location.as_ref().map(|location| {
+            let file = ledger
+                .files
+                .iter()
+                .position(|(path, _)| *path == location.path);
+            (file, location.line)
+        })
+    });
+}
+
+/// Verifies the lifecycle of the accounts.
+///
+/// Every account has to be opened once, and can only be used from the date of its open directive
+/// up to (and including) the date of its close directive. When closed, the account can not hold
+/// any units.
+fn check_accounts<D: Decimal>(ledger: &Ledger<D>) -> Vec<Error<D>> {
+    let mut errors = Vec::new();
+    let mut opened: HashMap<&Account, (&Directive<D>, Location)> = HashMap::new();
+    let mut closed: HashMap<&Account, chrono::NaiveDate> = HashMap::new();
+    for (path, d) in ledger.directives() {
+        match &d.content {
+            DirectiveContent::Open(open) => {
+                opened.entry(&open.account).or_insert((d, d.location(path)));
+            }
+            DirectiveContent::Close(close) => {
+                closed.entry(&close.account).or_insert(d.date);
+            }
+            _ => (),
+        }
+    }
+
+    // The problems of the balances are reported by check_balances.
+    let mut balances = Balances::new(ledger);
+    for (path, d) in ledger.directives() {
+        balances.apply(path, d);
+        if let DirectiveContent::Open(open) = &d.content {
+            if let Some((first, location)) = opened.get(&open.account) {
+                if !std::ptr::eq(*first, d) {
+                    errors.push(
+                        Error::DuplicateOpen {
+                            account: open.account.clone(),
+                            first: location.clone(),
+                        }
+                        .at(d.location(path)),
+                    );
+                }
+            }
+            continue;
+        }
+        for account in d.accounts() {
+            let err = match (opened.get(account), closed.get(account)) {
+                (None, _) => Error::AccountNotOpened {
+                    account: account.clone(),
+                },
+                (Some((open, _)), _) if d.date < open.date => Error::AccountNotOpenYet {
+                    account: account.clone(),
+                    opened: open.date,
+                },
+                (_, Some(date)) if d.date > *date => Error::AccountClosed {
+                    account: account.clone(),
+                    closed: *date,
+                },
+                _ => continue,
+            };
+            errors.push(err.at(d.location(path)));
+        }
+        if let DirectiveContent::Close(close) = &d.content {
+            let balance = balances.get(&close.account);
+            if !balance.is_zero() {
+                errors.push(
+                    Error::CloseWithBalance {
+                        account: close.account.clone(),
+                        balance,
+                    }
+                    .at(d.location(path)),
+                );
+            }
+        }
+    }
+    errors
+}
+
+/// A pad directive waiting for the balance directive that uses it.
+struct Pad<'a> {
+    source: &'a Account,
+    location: Location,
+    /// Currencies already padded, each currency is padded only by the first balance directive.
+    padded: HashSet<Currency>,
+    used: bool,
+}
+
+/// Running balances of the accounts, including the amounts inserted by the pad directives.
+///
+/// The directives have to be applied in the canonical order, see Ledger::directives.
+struct Balances<'a, D: Decimal> {
+    tolerance: Tolerance<D>,
+    accounts: HashMap<&'a Account, Bag<D>>,
+    pads: HashMap<&'a Account, Pad<'a>>,
+}
+
+impl<'a, D: Decimal> Balances<'a, D> {
+    fn new(ledger: &Ledger<D>) -> Self {
+        Balances {
+            tolerance: Tolerance::from_ledger(ledger).unwrap_or_default(),
+            accounts: HashMap::new(),
+            pads: HashMap::new(),
+        }
+    }
+
+    /// Returns the balance of the account, without its sub-accounts.
+    fn get(&self, account: &Account) -> Bag<D> {
+        self.accounts.get(account).cloned().unwrap_or_default()
+    }
+
+    /// Updates the balances with the directive and returns the problems found: the balance
+    /// directives that do not match and the pads replaced before being used.
+    fn apply(&mut self, path: &Path, d: &'a Directive<D>) -> Vec<Error<D>> {
+        let mut errors = Vec::new();
+        match &d.content {
+            DirectiveContent::Transaction(t) => {
+                for p in &t.postings {
+                    if let Some(units) = &p.amount {
+                        *self.accounts.entry(&p.account).or_default() += units.clone();
+                    }
+                }
+            }
+            DirectiveContent::Pad(pad) => {
+                let previous = self.pads.insert(
+                    &pad.account,
+                    Pad {
+                        source: &pad.source_account,
+                        location: d.location(path),
+                        padded: HashSet::new(),
+                        used: false,
+                    },
+                );
+                if let Some(previous) = previous.filter(|p| !p.used) {
+                    errors.push(
+                        Error::UnusedPad {
+                            account: pad.account.clone(),
+                        }
+                        .at(previous.location),
+                    );
+                }
+            }
+            DirectiveContent::Balance(balance) => {
+                let expected = &balance.amount;
+                let mut actual: D = self
+                    .accounts
+                    .iter()
+                    .filter(|(a, _)| is_same_or_child(a, &balance.account))
+                    .map(|(_, bag)| bag.get(&expected.currency))
+                    .fold(0.into(), |sum, v| sum + v);
+                let mut difference = actual.clone() - expected.value.clone();
+                let within = is_within(&difference, &self.tolerance.for_balance(expected));
+                if let Some(pad) = self.pads.get_mut(&balance.account) {
+                    // Like in beancount, the pad is used only if the balance would fail.
+                    if !within && pad.padded.insert(expected.currency.clone()) {
+                        let amount = Amount::new(-difference.clone(), expected.currency.clone());
+                        *self.accounts.entry(&balance.account).or_default() += amount.clone();
+                        *self.accounts.entry(pad.source).or_default() -= amount;
+                        pad.used = true;
+                        actual = expected.value.clone();
+                        difference = 0.into();
+                    }
+                }
+                if !is_within(&difference, &self.tolerance.for_balance(expected)) {
+                    errors.push(
+                        Error::BalanceMismatch {
+                            account: balance.account.clone(),
+                            expected: expected.clone(),
+                            actual: Amount::new(actual, expected.currency.clone()),
+                            difference: Amount::new(difference, expected.currency.clone()),
+                        }
+                        .at(d.location(path)),
+                    );
+                }
+            }
+            _ => (),
+        }
+        errors
+    }
+
+    /// Returns the problems of the pads that were never used.
+    fn unused_pads(self) -> Vec<Error<D>> {
+        let mut pads: Vec<(&Account, Pad)> = self.pads.into_iter().collect();
+        pads.sort_by_key(|(account, _)| *account);
+        pads.into_iter()
+            .filter(|(_, pad)| !pad.used)
+            .map(|(account, pad)| {
+                Error::UnusedPad {
+                    account: account.clone(),
+                }
+                .at(pad.location)
+            })
+            .collect()
+    }
+}
+
+/// Verifies the balance directives, the way beancount does.
+///
+/// The balance is checked at the beginning of the day, so it does not include the transactions of
+/// that day, and it includes the postings to all sub-accounts. The difference has to be within the
+/// tolerance implied by the precision of the amount. If the account was padded, the first balance
+/// directive of each currency inserts the missing amount, transferred from the source account of
+/// the pad.
+fn check_balances<D: Decimal>(ledger: &Ledger<D>) -> Vec<Error<D>> {
+    let mut errors = Vec::new();
+    let mut balances = Balances::new(ledger);
+    for (path, d) in ledger.directives() {
+        errors.extend(balances.apply(path, d));
+    }
+    errors.extend(balances.unused_pads());
+    errors
+}
+
+/// Verifies that the units of postings and balance directives use the currencies allowed by the
+/// open directive of the account (any currency, if the open directive does not list them).
 ///
-/// TODO: Add other checks, like verifying that accounts are open and verify balance
-/// directives.
-pub fn check<D: Decimal>(ledger: &Ledger<D>) -> anyhow::Result<()> {
-    let mut directives: Vec<&Directive<D>> = Vec::new();
-    for (_, file) in &ledger.files {
-        for d in &file.directives {
-            directives.push(d);
-            let t = match &d.content {
-                DirectiveContent::Transaction(t) => t,
+/// With the undeclared_commodities option, it also reports the first use of every commodity
+/// without a commodity directive.
+fn check_currencies<D: Decimal>(ledger: &Ledger<D>, options: &CheckOptions) -> Vec<Error<D>> {
+    let mut errors = Vec::new();
+    let mut allowed: HashMap<&Account, &HashSet<Currency>> = HashMap::new();
+    let mut declared: HashSet<&Currency> = HashSet::new();
+    for (_, d) in ledger.directives() {
+        match &d.content {
+            DirectiveContent::Open(open) => {
+                allowed.entry(&open.account).or_insert(&open.currencies);
+            }
+            DirectiveContent::Commodity(currency) => {
+                declared.insert(currency);
+            }
+            _ => (),
+        }
+    }
+
+    let mut reported: HashSet<&Currency> = HashSet::new();
+    for (path, d) in ledger.directives() {
+        let units: Vec<(&Account, &Currency)> = match &d.content {
+            DirectiveContent::Transaction(t) => t
+                .postings
+                .iter()
+                .filter_map(|p| Some((&p.account, &p.amount.as_ref()?.currency)))
+                .collect(),
+            DirectiveContent::Balance(b) => vec![(&b.account, &b.amount.currency)],
+            _ => Vec::new(),
+        };
+        for (account, currency) in units {
+            let currencies = match allowed.get(account) {
+                Some(currencies) if !currencies.is_empty() => currencies,
                 _ => continue,
             };
-            if !t.balanced {
-                println!("Transaction not balanced:\n{}", d);
+            if !currencies.contains(currency) {
+                let mut allowed: Vec<Currency> = currencies.iter().cloned().collect();
+                allowed.sort();
+                errors.push(
+                    Error::CurrencyNotAllowed {
+                        account: account.clone(),
+                        currency: currency.clone(),
+                        allowed,
+                    }
+                    .at(d.location(path)),
+                );
+            }
+        }
+        if !options.undeclared_commodities {
+            continue;
+        }
+        for currency in currencies(d) {
+            if !declared.contains(currency) && reported.insert(currency) {
+                errors.push(
+                    Error::UndeclaredCommodity {
+                        currency: currency.clone(),
+                    }
+                    .at(d.location(path)),
+                );
+            }
+        }
+    }
+    errors
+}
+
+/// Returns all currencies used by the directive.
+fn currencies<D>(d: &Directive<D>) -> Vec<&Currency> {
+    match &d.content {
+        DirectiveContent::Balance(b) => vec![&b.amount.currency],
+        DirectiveContent::Open(o) => {
+            let mut currencies: Vec<&Currency> = o.currencies.iter().collect();
+            currencies.sort();
+            currencies
+        }
+        DirectiveContent::Price(p) => vec![&p.currency, &p.amount.currency],
+        DirectiveContent::Transaction(t) => {
+            let mut currencies = Vec::new();
+            for p in &t.postings {
+                currencies.extend(p.amount.as_ref().map(|a| &a.currency));
+                currencies.extend(p.cost.as_ref().and_then(|c| c.currency.as_ref()));
+                currencies.extend(p.price.as_ref().map(|price| match price {
+                    PostingPrice::Unit(a) | PostingPrice::Total(a) => &a.currency,
+                }));
+            }
+            currencies
+        }
+        DirectiveContent::Close(_)
+        | DirectiveContent::Commodity(_)
+        | DirectiveContent::Event(_)
+        | DirectiveContent::Pad(_) => Vec::new(),
+    }
+}
+
+/// Returns true if the account is the parent account or one of its sub-accounts.
+pub(crate) fn is_same_or_child(account: &Account, parent: &Account) -> bool {
+    match account.as_str().strip_prefix(parent.as_str()) {
+        Some(rest) => rest.is_empty() || rest.starts_with(':'),
+        None => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    fn errors(
+        check: impl Fn(&Ledger<rust_decimal::Decimal>) -> Vec<Error>,
+        input: &str,
+    ) -> Vec<String> {
+        let ledger = Ledger {
+            files: vec![("main.beancount".into(), crate::parse(input).unwrap())],
+        };
+        check(&ledger).iter().map(|e| e.to_string()).collect()
+    }
+
+    #[test]
+    fn accepts_accounts_used_while_open() {
+        let input = r#"2020-01-01 open Assets:Cash
+2020-01-01 open Expenses:Food
+2020-01-01 * "Food"
+  Expenses:Food 10 CHF
+  Assets:Cash
+2020-01-02 * "Refund"
+  Expenses:Food -10 CHF
+  Assets:Cash
+2020-01-02 close Expenses:Food
+"#;
+        assert_eq!(errors(check_accounts, input), Vec::<String>::new());
+    }
+
+    #[test]
+    fn reports_accounts_used_outside_of_their_lifetime() {
+        let input = r#"2020-01-02 open Assets:Cash
+2020-01-03 close Assets:Cash
+2020-01-01 * "Before open"
+  Assets:Cash 10 CHF
+  Income:Salary
+2020-01-04 balance Assets:Cash 0 CHF
+2020-01-05 open Assets:Cash
+"#;
+        assert_eq!(
+            errors(check_accounts, input),
+            [
+                "main.beancount:3: account Assets:Cash is used before it is opened on 2020-01-02",
+                "main.beancount:3: account Income:Salary was never opened",
+                "main.beancount:2: account Assets:Cash is closed with non-zero balance (10 CHF)",
+                "main.beancount:6: account Assets:Cash is used after it is closed on 2020-01-03",
+                "main.beancount:7: account Assets:Cash is already opened at main.beancount:1",
+            ]
+        );
+    }
+
+    #[test]
+    fn closes_padded_accounts() {
+        let input = r#"2020-01-01 open Assets:Cash
+2020-01-01 open Equity:Opening-Balances
+2020-01-01 open Expenses:Food
+2020-01-01 pad Assets:Cash Equity:Opening-Balances
+2020-01-02 balance Assets:Cash 100 CHF
+2020-01-03 * "Food"
+  Assets:Cash -100 CHF
+  Expenses:Food
+2020-01-04 close Assets:Cash
+"#;
+        assert_eq!(errors(check_accounts, input), Vec::<String>::new());
+    }
+
+    #[test]
+    fn checks_balance_at_start_of_day_including_sub_accounts() {
+        let input = r#"2020-01-01 * "Deposit"
+  Assets:Bank:Checking 10.00 CHF
+  Assets:Bank:Savings 5.00 CHF
+  Income:Salary
+2020-01-02 balance Assets:Bank 15.00 CHF
+2020-01-02 * "Withdrawal"
+  Assets:Bank:Checking -1.00 CHF
+  Assets:Cash
+2020-01-02 balance Assets:Bank:Checking 10.01 CHF
+2020-01-02 balance Assets:Bank:Checking 10.02 CHF
+2020-01-03 balance Assets:Bank 15 CHF
+"#;
+        assert_eq!(
+            errors(check_balances, input),
+            [
+                "main.beancount:10: balance of Assets:Bank:Checking is 10.00 CHF, expected 10.02 CHF (difference -0.02 CHF)",
+                "main.beancount:11: balance of Assets:Bank is 14.00 CHF, expected 15 CHF (difference -1.00 CHF)",
+            ]
+        );
+    }
+
+    #[test]
+    fn reports_typed_balance_mismatch() {
+        let ledger = Ledger {
+            files: vec![(
+                "main.beancount".into(),
+                crate::parse("2020-01-02 balance Assets:Cash 10.00 CHF\n").unwrap(),
+            )],
+        };
+        let errors = check_balances(&ledger);
+        match errors[0].kind() {
+            Error::BalanceMismatch {
+                account,
+                expected,
+                actual,
+                difference,
+            } => {
+                assert_eq!(account.as_str(), "Assets:Cash");
+                assert_eq!(expected.value, "10.00".parse().unwrap());
+                assert_eq!(actual.value, 0.into());
+                assert_eq!(difference.value, "-10.00".parse().unwrap());
             }
+            err => panic!("unexpected error: {}", err),
         }
     }
-    Ok(())
+
+    #[test]
+    fn pads_first_balance_of_each_currency() {
+        let input = r#"2020-01-01 pad Assets:Cash Equity:Opening-Balances
+2020-01-03 balance Assets:Cash 10 CHF
+2020-01-03 balance Assets:Cash 5 USD
+2020-01-04 balance Assets:Cash 11 CHF
+2020-01-04 balance Equity:Opening-Balances -10 CHF
+2020-01-05 pad Assets:Cash Equity:Opening-Balances
+"#;
+        assert_eq!(
+            errors(check_balances, input),
+            [
+                "main.beancount:4: balance of Assets:Cash is 10 CHF, expected 11 CHF (difference -1 CHF)",
+                "main.beancount:6: pad of Assets:Cash is not used by any balance directive",
+            ]
+        );
+    }
+
+    #[test]
+    fn does_not_pad_differences_within_tolerance() {
+        let input = r#"2020-01-01 * "Deposit"
+  Assets:Cash 10.004 CHF
+  Income:Salary
+2020-01-02 pad Assets:Cash Equity:Opening-Balances
+2020-01-03 balance Assets:Cash 10.00 CHF
+2020-01-04 balance Equity:Opening-Balances 0.000 CHF
+"#;
+        assert_eq!(
+            errors(check_balances, input),
+            ["main.beancount:4: pad of Assets:Cash is not used by any balance directive"]
+        );
+    }
+
+    #[test]
+    fn checks_currencies_allowed_by_open() {
+        let input = r#"2020-01-01 open Assets:Revolut:CHF CHF
+2020-01-01 open Assets:Revolut:EUR EUR,USD
+2020-01-01 open Income:Salary
+2020-01-02 * "Salary"
+  Assets:Revolut:CHF 10 EUR
+  Assets:Revolut:EUR 10 USD
+  Income:Salary -20 GBP
+2020-01-03 balance Assets:Revolut:CHF 0 GBP
+"#;
+        let check = |l: &Ledger<_>| check_currencies(l, &CheckOptions::default());
+        assert_eq!(
+            errors(check, input),
+            [
+                "main.beancount:4: currency EUR is not allowed in Assets:Revolut:CHF, allowed: CHF",
+                "main.beancount:8: currency GBP is not allowed in Assets:Revolut:CHF, allowed: CHF",
+            ]
+        );
+    }
+
+    #[test]
+    fn reports_undeclared_commodities_once() {
+        let input = r#"2020-01-01 commodity CHF
+2020-01-01 open Assets:Cash CHF
+2020-01-02 * "Buy"
+  Assets:Stock 1 HOOL {10 CHF}
+  Assets:Cash -10 CHF
+2020-01-03 price HOOL 11 CHF
+"#;
+        let options = CheckOptions {
+            undeclared_commodities: true,
+            ..Default::default()
+        };
+        let check = |l: &Ledger<_>| check_currencies(l, &options);
+        assert_eq!(
+            errors(check, input),
+            ["main.beancount:3: commodity HOOL is not declared"]
+        );
+    }
+
+    #[test]
+    fn returns_diagnostics_ordered_by_location() {
+        let input = r#"2020-01-01 open Assets:Cash
+2020-01-02 * "Unbalanced"
+  Assets:Cash 10 CHF
+  Income:Salary -5 CHF
+2020-01-03 balance Assets:Cash 5 CHF
+"#;
+        let ledger: Ledger<rust_decimal::Decimal> = Ledger {
+            files: vec![("main.beancount".into(), crate::parse(input).unwrap())],
+        };
+        let diagnostics = check(&ledger);
+        let codes: Vec<(&str, Option<u32>)> = diagnostics
+            .iter()
+            .map(|d| (d.code, d.location.as_ref().and_then(|l| l.line)))
+            .collect();
+        assert_eq!(
+            codes,
+            [("E001", Some(2)), ("E101", Some(2)), ("E201", Some(5))]
+        );
+        assert!(diagnostics.iter().all(|d| d.is_error()));
+    }
 }
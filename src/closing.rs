@@ -1,21 +1,23 @@
-use crate::types::*;
+use crate::{routing::Routing, types::*};
 use chrono::Duration;
 use itertools::Itertools;
 use lazy_static::lazy_static;
 use regex::Regex;
 use std::io::Write;
 
-pub fn closing<D: Decimal>(ledger: &mut Ledger<D>, days: i64) -> anyhow::Result<()> {
+pub fn closing<D: Decimal>(
+    ledger: &mut Ledger<D>,
+    days: i64,
+    routing: &Routing,
+) -> crate::Result<(), D> {
     let mut next_closing_id = last_closing_id(ledger) + 1;
     let mut closing_accounts: Vec<(Account, Currency)> = Vec::new();
 
     let mut directives: Vec<&mut Directive<D>> = ledger
-        .files
-        .iter_mut()
-        .flat_map(|x| &mut x.1.directives)
-        .filter(|d| contains_closing_posting(d))
+        .transactions_mut()
+        .filter(contains_closing_posting)
+        .into_iter()
         .collect();
-    directives.sort_by_key(|d| d.date);
 
     println!("Found {} unmatched closing directives", directives.len());
 
@@ -45,9 +47,7 @@ pub fn closing<D: Decimal>(ledger: &mut Ledger<D>, days: i64) -> anyhow::Result<
         }
     }
 
-    balance_new_closing_accounts(&mut ledger.files[0].1, &closing_accounts);
-
-    Ok(())
+    balance_new_closing_accounts(ledger, &closing_accounts, routing)
 }
 
 fn match_directives<D: Decimal>(
@@ -57,7 +57,7 @@ fn match_directives<D: Decimal>(
     a: usize,
     b: usize,
 ) {
-    let account = Account(format!("Assets:Closing:{:06}", next_closing_id));
+    let account = Account::from(format!("Assets:Closing:{:06}", next_closing_id));
     *next_closing_id += 1;
 
     let amount_a = closing_posting(directives[a])
@@ -83,30 +83,34 @@ fn match_directives<D: Decimal>(
 }
 
 fn balance_new_closing_accounts<D: Decimal>(
-    file: &mut BeancountFile<D>,
+    ledger: &mut Ledger<D>,
     closing_accounts: &[(Account, Currency)],
-) {
+    routing: &Routing,
+) -> crate::Result<(), D> {
     for (account, currency) in closing_accounts {
-        file.directives.push(Directive {
-            date: chrono::NaiveDate::from_ymd_opt(2000, 1, 1).unwrap(),
-            content: DirectiveContent::Open(Open {
-                account: account.clone(),
-                currencies: [currency.clone()].into_iter().collect(),
-            }),
-            metadata: Default::default(),
-        });
-        file.directives.push(Directive {
-            date: chrono::NaiveDate::from_ymd_opt(2099, 1, 1).unwrap(),
-            content: DirectiveContent::Balance(Balance {
-                account: account.clone(),
-                amount: Amount {
-                    value: 0.into(),
-                    currency: currency.clone(),
-                },
-            }),
-            metadata: Default::default(),
-        });
+        ledger.insert(
+            Directive::new(
+                chrono::NaiveDate::from_ymd_opt(2000, 1, 1).unwrap(),
+                DirectiveContent::Open(Open {
+                    account: account.clone(),
+                    currencies: [currency.clone()].into_iter().collect(),
+                    booking_method: None,
+                }),
+            ),
+            routing,
+        )?;
+        ledger.insert(
+            Directive::new(
+                chrono::NaiveDate::from_ymd_opt(2099, 1, 1).unwrap(),
+                DirectiveContent::Balance(Balance {
+                    account: account.clone(),
+                    amount: Amount::new(0.into(), currency.clone()),
+                }),
+            ),
+            routing,
+        )?;
     }
+    Ok(())
 }
 
 fn find_best_matches<D: Decimal>(
@@ -124,7 +128,7 @@ fn find_best_matches<D: Decimal>(
         .metadata
         .get("closing_currency_hint")
         .and_then(|h| match h {
-            MetadataValue::String(x) => Some(Currency(x.clone())),
+            MetadataValue::String(x) => Some(Currency::new(x)),
             _ => None,
         })
         .unwrap_or_else(|| start_a.currency.clone());
@@ -191,7 +195,7 @@ fn parse_closing_id(account: &Account) -> Option<i32> {
     lazy_static! {
         static ref RE: Regex = Regex::new(r"^Assets:Closing:(\d{6})$").unwrap();
     }
-    RE.captures(&account.0)?
+    RE.captures(account.as_str())?
         .get(1)?
         .as_str()
         .parse::<i32>()
@@ -203,7 +207,7 @@ fn closing_posting_mut<D: Decimal>(d: &mut Directive<D>) -> Option<&mut Posting<
         DirectiveContent::Transaction(t) => t,
         _ => panic!("directive is not a transaction"),
     };
-    let closing = Account("Assets:Closing".into());
+    let closing = Account::new("Assets:Closing");
     t.postings.iter_mut().find(|p| p.account == closing)
 }
 
@@ -212,7 +216,7 @@ fn closing_posting<D: Decimal>(d: &Directive<D>) -> Option<&Posting<D>> {
         DirectiveContent::Transaction(t) => t,
         _ => panic!("directive is not a transaction"),
     };
-    let closing = Account("Assets:Closing".into());
+    let closing = Account::new("Assets:Closing");
     t.postings.iter().find(|p| p.account == closing)
 }
 
@@ -222,7 +226,7 @@ fn contains_closing_posting<D: Decimal>(d: &Directive<D>) -> bool {
         _ => return false,
     };
     // TODO: make it configurable
-    let closing = Account("Assets:Closing".into());
+    let closing = Account::new("Assets:Closing");
     t.postings.iter().any(|p| p.account == closing)
 }
 
@@ -236,7 +240,7 @@ mod tests {
         let cases = &[("Assets:Closing:000000", Some(0))];
 
         for (a, id) in cases {
-            assert_eq!(parse_closing_id(&Account(a.to_string())), *id);
+            assert_eq!(parse_closing_id(&Account::new(a)), *id);
         }
     }
 }
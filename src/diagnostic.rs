@@ -0,0 +1,203 @@
+//! Problems found in a ledger by check and the other validations, in a form that can be rendered
+//! for humans (like compiler errors) or consumed by other tools.
+use crate::{types::Decimal, Error, Location};
+use std::fmt::Display;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[cfg_attr(feature = "serialize", derive(serde::Serialize))]
+#[cfg_attr(feature = "serialize", serde(rename_all = "lowercase"))]
+pub enum Severity {
+    Warning,
+    Error,
+}
+
+impl Display for Severity {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Severity::Warning => write!(f, "warning"),
+            Severity::Error => write!(f, "error"),
+        }
+    }
+}
+
+/// A single problem found in the ledger.
+///
+/// Displayed in the style of compiler errors, e.g.
+/// `main.beancount:12: error[E201]: balance of Assets:Cash is ...`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serialize", derive(serde::Serialize))]
+pub struct Diagnostic {
+    pub severity: Severity,
+    /// Stable code of the kind of problem, e.g. `E001`.
+    pub code: &'static str,
+    pub message: String,
+    /// Location of the directive with the problem, if it is tied to one.
+    pub location: Option<Location>,
+    /// Locations of other directives involved, e.g. the first open of an account opened twice.
+    pub related: Vec<Location>,
+}
+
+impl Diagnostic {
+    /// Returns true if the diagnostic is an error (and not a warning).
+    pub fn is_error(&self) -> bool {
+        self.severity == Severity::Error
+    }
+}
+
+/// Returns the diagnostic describing the error, with the location attached to it (if any).
+impl<D: Decimal> From<Error<D>> for Diagnostic {
+    fn from(err: Error<D>) -> Self {
+        let (location, err) = match err {
+            Error::At { location, error } => (Some(location), *error),
+            err => (None, err),
+        };
+        let related = match err.kind() {
+            Error::DuplicateOpen { first, .. } => vec![first.clone()],
+            _ => Vec::new(),
+        };
+        // A document matching no directive is suspicious, but not wrong.
+        let severity = match err.kind() {
+            Error::OrphanDocument { .. } => Severity::Warning,
+            _ => Severity::Error,
+        };
+        Diagnostic {
+            severity,
+            code: code(err.kind()),
+            message: err.to_string(),
+            location,
+            related,
+        }
+    }
+}
+
+/// Returns the code of the error, which does not change between versions.
+fn code<D: Decimal>(err: &Error<D>) -> &'static str {
+    match err {
+        Error::Unbalanced { .. } => "E001",
+        Error::ManyAutoPostings { .. } => "E002",
+        Error::NoMatchingLot { .. } => "E003",
+        Error::NotEnoughUnits { .. } => "E004",
+        Error::AmbiguousReduction { .. } => "E005",
+        Error::NoneBookingMethod => "E006",
+        Error::InvalidOption { .. } => "E007",
+        Error::UnknownBookingMethod(_) => "E008",
+        Error::NothingToInterpolate { .. } => "E009",
+        Error::ManyUnknownCosts { .. } => "E010",
+        Error::UnknownCostWithAutoPosting { .. } => "E011",
+        Error::UnknownCostBalanced { .. } => "E012",
+        Error::UnknownCostManyCurrencies { .. } => "E013",
+        Error::UnknownCostCurrency { .. } => "E014",
+        Error::UnknownCostWithoutUnits { .. } => "E015",
+        Error::UnknownCost { .. } => "E016",
+        Error::MissingPriceNumber { .. } => "E017",
+        Error::AccountNotOpened { .. } => "E101",
+        Error::AccountNotOpenYet { .. } => "E102",
+        Error::AccountClosed { .. } => "E103",
+        Error::DuplicateOpen { .. } => "E104",
+        Error::CloseWithBalance { .. } => "E105",
+        Error::BalanceMismatch { .. } => "E201",
+        Error::UnusedPad { .. } => "E202",
+        Error::CurrencyNotAllowed { .. } => "E301",
+        Error::UndeclaredCommodity { .. } => "E302",
+        Error::MissingDocument { .. } => "E401",
+        Error::InvalidDocumentName { .. } => "E402",
+        Error::DocumentOutsideAccount { .. } => "E403",
+        Error::OrphanDocument { .. } => "E404",
+        Error::Read { .. } => "E901",
+        Error::Write { .. } => "E902",
+        Error::Parse { .. } => "E903",
+        Error::Import { .. } => "E904",
+        Error::Config { .. } => "E905",
+        Error::EmptyLedger => "E906",
+        // The context does not change the kind of the error.
+        Error::Posting { error, .. } | Error::At { error, .. } => code(error),
+    }
+}
+
+/// Writes the location as `path:line`. Diagnostics point at whole directives, so no column is
+/// written.
+fn write_location(
+    f: &mut std::fmt::Formatter<'_>,
+    location: Option<&Location>,
+) -> std::fmt::Result {
+    match location {
+        Some(Location {
+            path,
+            line: Some(line),
+        }) => write!(f, "{}:{}: ", path.display(), line),
+        Some(Location { path, line: None }) => write!(f, "{}: ", path.display()),
+        None => Ok(()),
+    }
+}
+
+impl Display for Diagnostic {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write_location(f, self.location.as_ref())?;
+        write!(f, "{}[{}]: {}", self.severity, self.code, self.message)?;
+        for related in &self.related {
+            writeln!(f)?;
+            write_location(f, Some(related))?;
+            write!(f, "note: related directive")?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    fn location(line: u32) -> Location {
+        Location {
+            path: "main.beancount".into(),
+            line: Some(line),
+        }
+    }
+
+    #[test]
+    fn displays_in_compiler_style() {
+        let d = Diagnostic::from(
+            Error::<rust_decimal::Decimal>::DuplicateOpen {
+                account: "Assets:Cash".into(),
+                first: location(1),
+            }
+            .at(location(7)),
+        );
+        assert_eq!(d.code, "E104");
+        assert_eq!(d.related, [location(1)]);
+        assert_eq!(
+            d.to_string(),
+            "main.beancount:7: error[E104]: account Assets:Cash is already opened at main.beancount:1\nmain.beancount:1: note: related directive"
+        );
+    }
+
+    #[test]
+    fn keeps_posting_context_in_message() {
+        let d = Diagnostic::from(
+            Error::<rust_decimal::Decimal>::NoneBookingMethod
+                .posting("Assets:Stock".into())
+                .at(location(3)),
+        );
+        assert_eq!(d.code, "E006");
+        assert_eq!(
+            d.to_string(),
+            "main.beancount:3: error[E006]: posting to Assets:Stock: booking method NONE does not match lots"
+        );
+    }
+
+    #[test]
+    fn reports_orphan_documents_as_warnings() {
+        let d = Diagnostic::from(
+            Error::<rust_decimal::Decimal>::OrphanDocument {
+                path: "docs/Assets/Cash/2020-01-31.receipt.pdf".into(),
+            }
+            .at(location(1)),
+        );
+        assert!(!d.is_error());
+        assert_eq!(
+            d.to_string(),
+            "main.beancount:1: warning[E404]: document docs/Assets/Cash/2020-01-31.receipt.pdf does not match any directive"
+        );
+    }
+}
@@ -0,0 +1,304 @@
+//! Validation of the documents of a ledger against the file system: the files referenced by the
+//! `document` metadata and the directories of the `documents` option.
+//!
+//! TODO: verify the files of `document` directives, and match them with the files of the
+//! `documents` directories, once the parser supports them (they are currently dropped when
+//! parsing).
+use crate::{
+    check::sort_by_location, diagnostic::Diagnostic, types::*, CheckOptions, Error, Location,
+};
+use std::{
+    collections::HashSet,
+    future::Future,
+    path::{Path, PathBuf},
+};
+
+/// The metadata keys whose values are paths of documents, e.g. `document: "receipts/shop.pdf"`.
+const DOCUMENT_KEYS: [&str; 2] = ["document", "attachment"];
+
+/// An entry of a directory, as listed by the read_dir function given to check_documents.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DirEntry {
+    /// The file name of the entry.
+    pub name: String,
+    pub is_dir: bool,
+}
+
+/// Checks the documents of the ledger against the file system and returns the problems found.
+///
+/// The files in the `document` (or `attachment`) metadata of directives and postings have to
+/// exist. The directories of the `documents` option have to contain only the directories of
+/// opened accounts (e.g. `Assets/Bank` for `Assets:Bank`), with files named `YYYY-MM-DD.name`.
+/// Those files are orphans when no directive matches them: they have to be referenced by the
+/// `document` metadata of a directive, or a directive has to use their account at their date.
+/// Hidden files and directories (starting with `.`, like `.DS_Store`) are ignored. Relative paths are resolved from the directory of the file that contains them, like in
+/// beancount.
+///
+/// It uses given read_dir function to list the entries of the directory at given path, the
+/// documents themselves are never read.
+pub async fn check_documents<D, F, R>(ledger: &Ledger<D>, read_dir: F) -> Vec<Error<D>>
+where
+    D: Decimal,
+    F: Fn(PathBuf) -> R,
+    R: Future<Output = std::io::Result<Vec<DirEntry>>>,
+{
+    let mut errors = Vec::new();
+    let mut referenced = HashSet::new();
+    for (path, file) in &ledger.files {
+        let base = path.parent().unwrap_or(Path::new(""));
+        for d in &file.directives {
+            let postings = match &d.content {
+                DirectiveContent::Transaction(t) => &t.postings[..],
+                _ => &[],
+            };
+            let documents = std::iter::once(&d.metadata)
+                .chain(postings.iter().map(|p| &p.metadata))
+                .flat_map(|metadata| DOCUMENT_KEYS.iter().filter_map(|key| metadata.get(*key)));
+            for document in documents {
+                if let MetadataValue::String(document) = document {
+                    let document = base.join(document);
+                    if !exists(&read_dir, &document).await {
+                        errors.push(Error::MissingDocument { path: document }.at(d.location(path)));
+                    } else {
+                        referenced.insert(document);
+                    }
+                }
+            }
+        }
+    }
+
+    let accounts: HashSet<&str> = ledger
+        .directives()
+        .filter_map(|(_, d)| match &d.content {
+            DirectiveContent::Open(open) => Some(open.account.as_str()),
+            _ => None,
+        })
+        .collect();
+    let used: HashSet<(chrono::NaiveDate, &str)> = ledger
+        .directives()
+        .flat_map(|(_, d)| d.accounts().into_iter().map(|a| (d.date, a.as_str())))
+        .collect();
+    for (path, file) in &ledger.files {
+        let base = path.parent().unwrap_or(Path::new(""));
+        let location = Location {
+            path: path.clone(),
+            line: None,
+        };
+        let roots = file.options.iter().filter(|(name, _)| name == "documents");
+        for (_, root) in roots {
+            // The directories to list, with the components of the account they stand for.
+            let mut queue: Vec<(PathBuf, Vec<String>)> = vec![(base.join(root), Vec::new())];
+            while let Some((dir, components)) = queue.pop() {
+                let mut entries = match read_dir(dir.clone()).await {
+                    Ok(entries) => entries,
+                    Err(source) => {
+                        errors.push(Error::Read { path: dir, source }.at(location.clone()));
+                        continue;
+                    }
+                };
+                entries.sort_by(|a, b| a.name.cmp(&b.name));
+                let account = components.join(":");
+                let mut subdirs = Vec::new();
+                for entry in entries {
+                    if entry.name.starts_with('.') {
+                        continue;
+                    }
+                    let path = dir.join(&entry.name);
+                    if entry.is_dir {
+                        let mut components = components.clone();
+                        components.push(entry.name);
+                        subdirs.push((path, components));
+                    } else if !accounts.contains(account.as_str()) {
+                        errors.push(Error::DocumentOutsideAccount { path }.at(location.clone()));
+                    } else if let Some(date) = document_date(&entry.name) {
+                        if !referenced.contains(&path) && !used.contains(&(date, account.as_str()))
+                        {
+                            errors.push(Error::OrphanDocument { path }.at(location.clone()));
+                        }
+                    } else {
+                        errors.push(Error::InvalidDocumentName { path }.at(location.clone()));
+                    }
+                }
+                // The queue is a stack, the directories are pushed in reverse to list them in
+                // order.
+                queue.extend(subdirs.into_iter().rev());
+            }
+        }
+    }
+    errors
+}
+
+/// Same as check_with_options, but also checks the documents of the ledger with given read_dir
+/// function (see check_documents). The problems are ordered by their location.
+pub async fn check_with_documents<D, F, R>(
+    ledger: &Ledger<D>,
+    options: &CheckOptions,
+    read_dir: F,
+) -> Vec<Diagnostic>
+where
+    D: Decimal,
+    F: Fn(PathBuf) -> R,
+    R: Future<Output = std::io::Result<Vec<DirEntry>>>,
+{
+    let mut diagnostics = crate::check_with_options(ledger, options);
+    diagnostics.extend(
+        check_documents(ledger, read_dir)
+            .await
+            .into_iter()
+            .map(Diagnostic::from),
+    );
+    sort_by_location(ledger, &mut diagnostics);
+    diagnostics
+}
+
+/// Returns true if the file exists, by looking for it in the listing of its directory.
+async fn exists<F, R>(read_dir: &F, path: &Path) -> bool
+where
+    F: Fn(PathBuf) -> R,
+    R: Future<Output = std::io::Result<Vec<DirEntry>>>,
+{
+    let (Some(dir), Some(name)) = (path.parent(), path.file_name()) else {
+        return false;
+    };
+    match read_dir(dir.into()).await {
+        Ok(entries) => entries.iter().any(|e| !e.is_dir && *name == *e.name),
+        Err(_) => false,
+    }
+}
+
+/// Returns the date of the file if it is named `YYYY-MM-DD.name`, with a valid date.
+fn document_date(name: &str) -> Option<chrono::NaiveDate> {
+    let (date, rest) = (name.get(..10)?, name.get(10..)?);
+    if rest.len() > 1 && rest.starts_with('.') {
+        chrono::NaiveDate::parse_from_str(date, "%Y-%m-%d").ok()
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+    use std::collections::HashMap;
+
+    /// Checks the documents of the input against the directories, given as paths and the
+    /// entries in them (directories end with `/`).
+    async fn errors(input: &str, dirs: &[(&str, &[&str])]) -> Vec<String> {
+        let ledger: Ledger<rust_decimal::Decimal> = Ledger {
+            files: vec![("ledger/main.beancount".into(), crate::parse(input).unwrap())],
+        };
+        let dirs: HashMap<PathBuf, Vec<DirEntry>> = dirs
+            .iter()
+            .map(|(path, entries)| {
+                let entries = entries
+                    .iter()
+                    .map(|e| DirEntry {
+                        name: e.trim_end_matches('/').into(),
+                        is_dir: e.ends_with('/'),
+                    })
+                    .collect();
+                (PathBuf::from(path), entries)
+            })
+            .collect();
+        let read_dir = |path: PathBuf| {
+            let entries = dirs.get(&path).cloned().ok_or_else(|| {
+                std::io::Error::new(std::io::ErrorKind::NotFound, "no such directory")
+            });
+            std::future::ready(entries)
+        };
+        check_documents(&ledger, read_dir)
+            .await
+            .iter()
+            .map(|e| e.to_string())
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn reports_missing_documents_of_metadata() {
+        let input = r#"2020-01-01 * "Shop"
+  document: "receipts/shop.pdf"
+  Expenses:Food 10 CHF
+    attachment: "receipts/missing.pdf"
+  Assets:Cash
+2020-01-02 * "Other shop"
+  document: "elsewhere/shop.pdf"
+  Expenses:Food 10 CHF
+  Assets:Cash
+"#;
+        assert_eq!(
+            errors(input, &[("ledger/receipts", &["shop.pdf"])]).await,
+            [
+                "ledger/main.beancount:1: document ledger/receipts/missing.pdf does not exist",
+                "ledger/main.beancount:6: document ledger/elsewhere/shop.pdf does not exist",
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn checks_layout_of_documents_directories() {
+        let input = r#"option "documents" "docs"
+option "documents" "missing"
+2020-01-01 open Assets:Bank
+2020-01-01 open Assets:Bank:Savings
+  document: "docs/Assets/Bank/2020-01-02.contract.pdf"
+2020-01-31 balance Assets:Bank 0 CHF
+"#;
+        let dirs: &[(&str, &[&str])] = &[
+            (
+                "ledger/docs",
+                &[".DS_Store", ".git/", "Assets/", "notes.txt"],
+            ),
+            ("ledger/docs/Assets", &["Bank/", "Cash/"]),
+            (
+                "ledger/docs/Assets/Bank",
+                &[
+                    "2020-01-02.contract.pdf",
+                    "2020-01-31.statement.pdf",
+                    "2020-02-29.statement.pdf",
+                    "Savings/",
+                    "scan.pdf",
+                ],
+            ),
+            (
+                "ledger/docs/Assets/Bank/Savings",
+                &["2020-02-30.statement.pdf"],
+            ),
+            ("ledger/docs/Assets/Cash", &["2020-01-31.receipt.pdf"]),
+        ];
+        assert_eq!(
+            errors(input, dirs).await,
+            [
+                "ledger/main.beancount: document ledger/docs/notes.txt is not in the directory of an opened account",
+                "ledger/main.beancount: document ledger/docs/Assets/Bank/2020-02-29.statement.pdf does not match any directive",
+                "ledger/main.beancount: document ledger/docs/Assets/Bank/scan.pdf is not named YYYY-MM-DD.name",
+                "ledger/main.beancount: document ledger/docs/Assets/Bank/Savings/2020-02-30.statement.pdf is not named YYYY-MM-DD.name",
+                "ledger/main.beancount: document ledger/docs/Assets/Cash/2020-01-31.receipt.pdf is not in the directory of an opened account",
+                "ledger/main.beancount: failed to read ledger/missing",
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn sorts_problems_of_documents_with_the_others() {
+        let input = r#"2020-01-01 open Assets:Cash
+  document: "missing.pdf"
+2020-01-02 * "Shop"
+  Expenses:Food 10 CHF
+  Assets:Cash
+"#;
+        let ledger: Ledger<rust_decimal::Decimal> = Ledger {
+            files: vec![("main.beancount".into(), crate::parse(input).unwrap())],
+        };
+        let read_dir = |_| std::future::ready(Ok(Vec::new()));
+        let diagnostics = check_with_documents(&ledger, &Default::default(), read_dir).await;
+        let diagnostics: Vec<_> = diagnostics.iter().map(|d| d.to_string()).collect();
+        assert_eq!(
+            diagnostics,
+            [
+                "main.beancount:1: error[E401]: document missing.pdf does not exist",
+                "main.beancount:3: error[E101]: account Expenses:Food was never opened",
+            ]
+        );
+    }
+}
@@ -0,0 +1,232 @@
+//! Detection of transactions that were likely recorded twice, e.g. when the same statement is
+//! imported again or a transfer is recorded from both sides.
+use crate::{query::matches, types::*};
+use std::{collections::HashMap, path::PathBuf};
+
+/// Settings of the duplicate detection, the `[duplicates]` table of `beanru.toml`:
+///
+/// ```toml
+/// [duplicates]
+/// days = 3
+/// similarity = 0.8
+/// id-links = "id-*"
+/// ```
+#[derive(Debug, Clone, PartialEq, serde::Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields, default)]
+pub struct DuplicateConfig {
+    /// Maximum number of days between the dates of duplicates.
+    pub days: i64,
+    /// Minimum similarity of the postings (from 0 to 1) for the transactions to be duplicates,
+    /// see similarity. With 1, the postings have to be the same.
+    pub similarity: f64,
+    /// Pattern of the links that identify a transaction, like the `id-` links added by the
+    /// importers (`*` matches any characters). Transactions sharing such a link are duplicates.
+    /// Other links are shared by related transactions, like an invoice and its payment.
+    pub id_links: String,
+}
+
+impl Default for DuplicateConfig {
+    fn default() -> Self {
+        Self {
+            days: 0,
+            similarity: 1.0,
+            id_links: "id-*".into(),
+        }
+    }
+}
+
+/// A transaction that looks like a duplicate of an earlier one.
+#[derive(Debug, Clone)]
+pub struct Duplicate<'a, D> {
+    pub path: &'a PathBuf,
+    pub directive: &'a Directive<D>,
+    /// The earlier transaction.
+    pub original: (&'a PathBuf, &'a Directive<D>),
+    /// Why the transactions are considered duplicates, e.g. `same link ^id-1`.
+    pub reason: String,
+}
+
+/// Returns the transactions that look like duplicates of an earlier transaction.
+///
+/// Transactions are duplicates if they have a common link matching `id_links` (regardless of
+/// their dates), or if they are at most `days` apart and the similarity of their postings is at
+/// least `similarity`.
+/// Transactions with `duplicate-ok: TRUE` metadata are never reported (nor used as originals).
+pub fn find_duplicates<'a, D: Decimal>(
+    ledger: &'a Ledger<D>,
+    config: &DuplicateConfig,
+) -> Vec<Duplicate<'a, D>> {
+    let transactions: Vec<(&PathBuf, &Directive<D>, &Transaction<D>)> = ledger
+        .directives()
+        .filter(|(_, d)| !is_duplicate_ok(d))
+        .filter_map(|(path, d)| Some((path, d, d.content.transaction_opt()?)))
+        .collect();
+    let mut links: HashMap<&str, usize> = HashMap::new();
+    let mut res = Vec::new();
+    for (i, (path, d, t)) in transactions.iter().enumerate() {
+        let mut found: Option<(usize, String)> = None;
+        for link in t.links.iter().filter(|l| matches(&config.id_links, l)) {
+            match links.get(link.as_str()) {
+                Some(j) if found.is_none() => found = Some((*j, format!("same link ^{}", link))),
+                Some(_) => (),
+                None => {
+                    links.insert(link, i);
+                }
+            }
+        }
+        if found.is_none() {
+            found = transactions[..i]
+                .iter()
+                .enumerate()
+                .rev()
+                .take_while(|(_, (_, o, _))| (d.date - o.date).num_days() <= config.days)
+                .map(|(j, (_, _, o))| (j, similarity(t, o)))
+                .find(|(_, s)| *s >= config.similarity)
+                .map(|(j, s)| (j, format!("{:.0}% similar postings", s * 100.0)));
+        }
+        if let Some((j, reason)) = found {
+            let (original_path, original, _) = transactions[j];
+            res.push(Duplicate {
+                path,
+                directive: d,
+                original: (original_path, original),
+                reason,
+            });
+        }
+    }
+    res
+}
+
+/// Returns the similarity of the postings of two transactions, from 0 to 1.
+///
+/// It is the share of postings (with the same account and units) present in both transactions,
+/// so 1 means that the transactions have the same postings, in any order.
+pub fn similarity<D: Decimal>(a: &Transaction<D>, b: &Transaction<D>) -> f64 {
+    let total = a.postings.len() + b.postings.len();
+    if total == 0 {
+        return 0.0;
+    }
+    let mut unmatched: Vec<&Posting<D>> = b.postings.iter().collect();
+    let mut matched = 0;
+    for p in &a.postings {
+        let pos = unmatched
+            .iter()
+            .position(|o| o.account == p.account && o.amount == p.amount);
+        if let Some(pos) = pos {
+            unmatched.swap_remove(pos);
+            matched += 1;
+        }
+    }
+    (2 * matched) as f64 / total as f64
+}
+
+fn is_duplicate_ok<D>(d: &Directive<D>) -> bool {
+    match d.metadata.get("duplicate-ok") {
+        Some(MetadataValue::Currency(c)) => c.as_str() == "TRUE",
+        Some(MetadataValue::String(s)) => s.eq_ignore_ascii_case("true"),
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    fn duplicates(
+        config: &DuplicateConfig,
+        input: &str,
+    ) -> Vec<(Option<u32>, Option<u32>, String)> {
+        let ledger: Ledger<rust_decimal::Decimal> = Ledger {
+            files: vec![("main.beancount".into(), crate::parse(input).unwrap())],
+        };
+        find_duplicates(&ledger, config)
+            .into_iter()
+            .map(|d| (d.directive.line, d.original.1.line, d.reason))
+            .collect()
+    }
+
+    #[test]
+    fn finds_same_links_and_postings() {
+        let input = r#"2020-01-01 * "Coffee" ^id-1
+  Expenses:Food 3 CHF
+  Assets:Cash
+2020-01-01 * "Transfer"
+  Assets:Bank -100 CHF
+  Assets:Revolut 100 CHF
+2020-01-02 * "Transfer"
+  Assets:Revolut 100 CHF
+  Assets:Bank -100 CHF
+2020-02-01 * "Coffee again" ^id-1
+  Expenses:Food 4 CHF
+  Assets:Cash
+"#;
+        let config = DuplicateConfig {
+            days: 1,
+            ..Default::default()
+        };
+        assert_eq!(
+            duplicates(&config, input),
+            [
+                (Some(7), Some(4), "100% similar postings".into()),
+                (Some(10), Some(1), "same link ^id-1".into()),
+            ]
+        );
+        assert_eq!(
+            duplicates(&DuplicateConfig::default(), input),
+            [(Some(10), Some(1), "same link ^id-1".into())]
+        );
+    }
+
+    #[test]
+    fn ignores_links_not_matching_id_pattern() {
+        let input = r#"2020-01-01 * "Invoice" ^invoice-7
+  Expenses:Home 100 CHF
+  Liabilities:Payable
+2020-01-20 * "Payment" ^invoice-7
+  Liabilities:Payable 100 CHF
+  Assets:Bank
+"#;
+        let config = DuplicateConfig {
+            days: 30,
+            similarity: 0.5,
+            ..Default::default()
+        };
+        assert_eq!(duplicates(&config, input), []);
+        let config = DuplicateConfig {
+            id_links: "invoice-*".into(),
+            ..Default::default()
+        };
+        assert_eq!(
+            duplicates(&config, input),
+            [(Some(4), Some(1), "same link ^invoice-7".into())]
+        );
+    }
+
+    #[test]
+    fn uses_similarity_threshold_and_allow_list() {
+        let input = r#"2020-01-01 * "Shop"
+  Expenses:Food 10 CHF
+  Expenses:Home 5 CHF
+  Assets:Cash -15 CHF
+2020-01-01 * "Shop"
+  Expenses:Food 10 CHF
+  Expenses:Home 6 CHF
+  Assets:Cash -16 CHF
+2020-01-01 * "Shop"
+  duplicate-ok: TRUE
+  Expenses:Food 10 CHF
+  Expenses:Home 5 CHF
+  Assets:Cash -15 CHF
+"#;
+        assert_eq!(duplicates(&DuplicateConfig::default(), input), []);
+        let config = DuplicateConfig {
+            similarity: 0.3,
+            ..Default::default()
+        };
+        assert_eq!(
+            duplicates(&config, input),
+            [(Some(5), Some(1), "33% similar postings".into())]
+        );
+    }
+}
@@ -0,0 +1,260 @@
+use crate::{
+    bag::Bag,
+    inventory::Lot,
+    types::{Account, Amount, Cost, Currency, Decimal},
+};
+use std::{fmt::Display, path::PathBuf};
+
+/// Result type used by all functions of this crate.
+///
+/// The amounts in the errors use the decimal type of the ledger, rust_decimal by default.
+pub type Result<T, D = rust_decimal::Decimal> = std::result::Result<T, Error<D>>;
+
+/// Location of a directive in the ledger.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serialize", derive(serde::Serialize))]
+pub struct Location {
+    pub path: PathBuf,
+    /// Line of the directive (starting from 1), if known. Directives created by scripts do not
+    /// have one.
+    pub line: Option<u32>,
+}
+
+impl std::fmt::Display for Location {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.line {
+            Some(line) => write!(f, "{}:{}", self.path.display(), line),
+            None => write!(f, "{}", self.path.display()),
+        }
+    }
+}
+
+/// Errors returned by this crate.
+#[derive(Debug, thiserror::Error)]
+#[non_exhaustive]
+pub enum Error<D: Decimal = rust_decimal::Decimal> {
+    /// The injected reader failed to read the file.
+    #[error("failed to read {}", path.display())]
+    Read {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+    /// The injected writer failed to write the file.
+    #[error("failed to write {}", path.display())]
+    Write {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+    /// The file is not a valid beancount file.
+    #[error("invalid beancount syntax at {}", parse_location(path, source))]
+    Parse {
+        /// Path of the file, None when parsing a string.
+        path: Option<PathBuf>,
+        source: beancount_parser::Error,
+    },
+    /// The importer failed to read the statement.
+    #[error("failed to import {}", path.display())]
+    Import {
+        path: PathBuf,
+        source: Box<dyn std::error::Error + Send + Sync>,
+    },
+    /// The configuration file (`beanru.toml`) is not valid.
+    #[error("invalid configuration")]
+    Config { source: toml::de::Error },
+    /// An option of the ledger has invalid value.
+    #[error("invalid value of option {name}: {value:?}")]
+    InvalidOption { name: String, value: String },
+    #[error("unknown booking method: {0}")]
+    UnknownBookingMethod(String),
+    /// The transaction does not balance, the residual is the sum of the weights of all postings
+    /// (only the currencies above the tolerance).
+    #[error("transaction does not balance: {}", join(residual))]
+    Unbalanced { residual: Vec<Amount<D>> },
+    /// More than one posting of the transaction has no amount.
+    #[error("more than one posting without amount: {first} and {second}")]
+    ManyAutoPostings { first: Account, second: Account },
+    /// The posting has no amount, but there is nothing to balance.
+    #[error("posting to {account} has no amount, but the transaction is already balanced")]
+    NothingToInterpolate { account: Account },
+    /// More than one posting of the transaction has an incomplete cost.
+    #[error("cannot infer the cost of more than one posting: {first} and {second}")]
+    ManyUnknownCosts { first: Account, second: Account },
+    /// The cost can not be inferred, as another posting has no amount.
+    #[error(
+        "cannot infer the cost of posting to {account}, as the posting to {auto} has no amount"
+    )]
+    UnknownCostWithAutoPosting { account: Account, auto: Account },
+    /// The cost can not be inferred, as the other postings are already balanced.
+    #[error(
+        "cannot infer the cost of posting to {account}, the other postings are already balanced"
+    )]
+    UnknownCostBalanced { account: Account },
+    /// The cost can not be inferred, as more than one currency does not balance.
+    #[error("cannot infer the cost of posting to {account}, more than one currency does not balance: {}", join(residual))]
+    UnknownCostManyCurrencies {
+        account: Account,
+        residual: Vec<Amount<D>>,
+    },
+    /// The currency of the cost is not the currency that does not balance.
+    #[error("cannot infer the cost of posting to {account}, the cost is in {cost} but {residual} does not balance")]
+    UnknownCostCurrency {
+        account: Account,
+        cost: Currency,
+        residual: Currency,
+    },
+    /// The cost can not be inferred, as the posting has no units.
+    #[error("cannot infer the cost of posting to {account}, it has no units")]
+    UnknownCostWithoutUnits { account: Account },
+    /// The cost spec of the posting does not determine the cost of its lot.
+    #[error("cannot determine the cost of posting to {account}")]
+    UnknownCost { account: Account },
+    /// The price of the posting has no number, like `10 HOOL @ USD`. Inferring the price from
+    /// the other postings is not supported.
+    #[error("price of posting to {account} has no number, inferring it is not supported")]
+    MissingPriceNumber { account: Account },
+    /// No lot in the inventory matches the cost spec of a reduction.
+    #[error("no lot of {currency} matches {spec}")]
+    NoMatchingLot {
+        currency: Currency,
+        spec: Box<Cost<D>>,
+    },
+    /// The matching lots hold less units than the reduction.
+    #[error("not enough units of {currency} matching {spec}: requested {requested}, available {available}")]
+    NotEnoughUnits {
+        currency: Currency,
+        spec: Box<Cost<D>>,
+        requested: D,
+        available: D,
+    },
+    /// Many lots match the reduction and the booking method can not choose between them.
+    #[error("ambiguous reduction of {units} matching {spec}, {} lots match: {}", lots.len(), join(lots))]
+    AmbiguousReduction {
+        units: Amount<D>,
+        spec: Box<Cost<D>>,
+        lots: Vec<Lot<D>>,
+    },
+    /// The account is used, but there is no open directive for it.
+    #[error("account {account} was never opened")]
+    AccountNotOpened { account: Account },
+    /// The account is used before the date of its open directive.
+    #[error("account {account} is used before it is opened on {opened}")]
+    AccountNotOpenYet {
+        account: Account,
+        opened: chrono::NaiveDate,
+    },
+    /// The account is used after the date of its close directive.
+    #[error("account {account} is used after it is closed on {closed}")]
+    AccountClosed {
+        account: Account,
+        closed: chrono::NaiveDate,
+    },
+    /// The account is opened more than once.
+    #[error("account {account} is already opened at {first}")]
+    DuplicateOpen { account: Account, first: Location },
+    /// The account is closed while it still holds some units.
+    #[error("account {account} is closed with non-zero balance {balance}")]
+    CloseWithBalance { account: Account, balance: Bag<D> },
+    /// The balance of the account (including its sub-accounts) does not match the balance
+    /// directive.
+    #[error("balance of {account} is {actual}, expected {expected} (difference {difference})")]
+    BalanceMismatch {
+        account: Account,
+        expected: Amount<D>,
+        actual: Amount<D>,
+        difference: Amount<D>,
+    },
+    /// The pad directive is not followed by a balance directive that needs padding.
+    #[error("pad of {account} is not used by any balance directive")]
+    UnusedPad { account: Account },
+    /// The open directive of the account does not allow the currency.
+    #[error("currency {currency} is not allowed in {account}, allowed: {}", allowed.iter().map(|c| c.as_str()).collect::<Vec<_>>().join(","))]
+    CurrencyNotAllowed {
+        account: Account,
+        currency: Currency,
+        /// The currencies allowed by the open directive, sorted.
+        allowed: Vec<Currency>,
+    },
+    /// The commodity is used, but there is no commodity directive for it.
+    #[error("commodity {currency} is not declared")]
+    UndeclaredCommodity { currency: Currency },
+    /// The document in the metadata of a directive does not exist.
+    #[error("document {} does not exist", path.display())]
+    MissingDocument { path: PathBuf },
+    /// The file in a `documents` directory is not named `YYYY-MM-DD.name`.
+    #[error("document {} is not named YYYY-MM-DD.name", path.display())]
+    InvalidDocumentName { path: PathBuf },
+    /// The file in a `documents` directory is not in the directory of an opened account.
+    #[error("document {} is not in the directory of an opened account", path.display())]
+    DocumentOutsideAccount { path: PathBuf },
+    /// The file in a `documents` directory matches no directive of the ledger.
+    #[error("document {} does not match any directive", path.display())]
+    OrphanDocument { path: PathBuf },
+    /// The operation needs the ledger to have at least one file.
+    #[error("the ledger has no files")]
+    EmptyLedger,
+    /// Lots were matched with the NONE booking method.
+    #[error("booking method NONE does not match lots")]
+    NoneBookingMethod,
+    /// Error of the posting to given account.
+    #[error("posting to {account}: {}", &**error)]
+    Posting {
+        account: Account,
+        error: Box<Error<D>>,
+    },
+    /// Error of the directive at given location.
+    #[error("{location}: {}", &**error)]
+    At {
+        location: Location,
+        error: Box<Error<D>>,
+    },
+}
+
+/// Joins the items with commas, for the messages of the errors.
+fn join<T: Display>(items: &[T]) -> String {
+    items
+        .iter()
+        .map(|item| item.to_string())
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+fn parse_location(path: &Option<PathBuf>, source: &beancount_parser::Error) -> String {
+    match path {
+        Some(path) => format!("{}:{}", path.display(), source.line_number()),
+        None => format!("line {}", source.line_number()),
+    }
+}
+
+impl<D: Decimal> Error<D> {
+    /// Attaches the location of the directive to the error.
+    pub fn at(self, location: Location) -> Self {
+        Error::At {
+            location,
+            error: Box::new(self),
+        }
+    }
+
+    /// Attaches the account of the posting to the error.
+    pub fn posting(self, account: Account) -> Self {
+        Error::Posting {
+            account,
+            error: Box::new(self),
+        }
+    }
+
+    /// Returns the location of the directive that caused the error, if known.
+    pub fn location(&self) -> Option<&Location> {
+        match self {
+            Error::At { location, .. } => Some(location),
+            _ => None,
+        }
+    }
+
+    /// Returns the error without the location and posting context.
+    pub fn kind(&self) -> &Error<D> {
+        match self {
+            Error::At { error, .. } | Error::Posting { error, .. } => error.kind(),
+            e => e,
+        }
+    }
+}
@@ -166,10 +166,10 @@ impl<'a> Lexer<'a> {
     }
 }
 
-fn read_token(content: &str) -> (Token, &str) {
+fn read_token(content: &str) -> (Token<'_>, &str) {
     let mut chars = content.char_indices();
     match chars.next() {
-        None => return (Token::Eol(""), ""),
+        None => (Token::Eol(""), ""),
         Some((p, '\n')) => {
             let (a, b) = content.split_at(p + 1);
             (Token::Eol(a), b)
@@ -348,7 +348,7 @@ fn split_at_newline(content: &str) -> (&str, &str) {
 }
 
 impl File {
-    pub fn parse(filename: impl Into<String>, content: &str) -> anyhow::Result<File> {
+    pub fn parse(filename: impl Into<String>, content: &str) -> crate::Result<File> {
         Ok(File {
             filename: filename.into(),
             entries: parse_root(content),
@@ -364,9 +364,9 @@ impl File {
     }
 }
 
-impl ToString for File {
-    fn to_string(&self) -> String {
-        self.entries.iter().map(|e| e.to_string()).join("")
+impl std::fmt::Display for File {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.entries.iter().map(|e| e.to_string()).join(""))
     }
 }
 
@@ -408,14 +408,14 @@ impl Entry {
     }
 }
 
-impl ToString for Entry {
-    fn to_string(&self) -> String {
+impl std::fmt::Display for Entry {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
-            Entry::Directive(d) => d.to_string(),
+            Entry::Directive(d) => write!(f, "{}", d),
             Entry::Pragma(_) => todo!(),
-            Entry::CommentBlock(c) => c.iter().join(""),
-            Entry::IgnoredLine(l) => l.into(),
-            Entry::InvalidLine(l) => l.into(),
+            Entry::CommentBlock(c) => f.write_str(&c.iter().join("")),
+            Entry::IgnoredLine(l) => f.write_str(l),
+            Entry::InvalidLine(l) => f.write_str(l),
         }
     }
 }
@@ -443,12 +443,12 @@ impl Directive {
     }
 }
 
-impl ToString for Directive {
-    fn to_string(&self) -> String {
+impl std::fmt::Display for Directive {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
-            Directive::Transaction(t) => t.to_string(),
-            Directive::Open(d) => d.to_string(),
-            Directive::Close(d) => d.to_string(),
+            Directive::Transaction(t) => write!(f, "{}", t),
+            Directive::Open(d) => write!(f, "{}", d),
+            Directive::Close(d) => write!(f, "{}", d),
         }
     }
 }
@@ -482,11 +482,11 @@ pub enum TagOrLink {
     Link(String),
 }
 
-impl ToString for TagOrLink {
-    fn to_string(&self) -> String {
+impl std::fmt::Display for TagOrLink {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
-            TagOrLink::Tag(x) => x.clone(),
-            TagOrLink::Link(x) => x.clone(),
+            TagOrLink::Tag(x) => f.write_str(x),
+            TagOrLink::Link(x) => f.write_str(x),
         }
     }
 }
@@ -506,9 +506,9 @@ impl<T> WithWS<T> {
     }
 }
 
-impl<T: ToString> ToString for WithWS<T> {
-    fn to_string(&self) -> String {
-        format!("{}{}", self.pre_ws, self.v.to_string())
+impl<T: std::fmt::Display> std::fmt::Display for WithWS<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}{}", self.pre_ws, self.v)
     }
 }
 
@@ -533,14 +533,14 @@ impl Open {
     }
 }
 
-impl ToString for Open {
-    fn to_string(&self) -> String {
-        format!(
-            "{}{}{}{}{}{}{}{}",
+impl std::fmt::Display for Open {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}{}{}open{}{}{}{}",
             self.pre_comments.join(""),
-            self.date.to_string(),
+            self.date,
             self.ws1,
-            "open",
             self.ws2,
             self.account,
             self.ws3,
@@ -554,9 +554,9 @@ pub struct Close {
     node: String,
 }
 
-impl ToString for Close {
-    fn to_string(&self) -> String {
-        self.node.clone()
+impl std::fmt::Display for Close {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.node)
     }
 }
 
@@ -610,9 +610,10 @@ impl Date {
     }
 }
 
-impl ToString for Date {
-    fn to_string(&self) -> String {
-        format!(
+impl std::fmt::Display for Date {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
             "{:04}{3}{:02}{3}{:02}",
             self.d.year(),
             self.d.month(),
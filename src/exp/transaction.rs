@@ -28,14 +28,9 @@ impl Transaction {
     }
 }
 
-impl ToString for Transaction {
-    fn to_string(&self) -> String {
-        let mut ret = format!(
-            "{}{}{}",
-            self.pre_comments.join(""),
-            self.date.to_string(),
-            self.typ.to_string()
-        );
+impl std::fmt::Display for Transaction {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut ret = format!("{}{}{}", self.pre_comments.join(""), self.date, self.typ);
         if let Some(ref payee) = self.payee {
             ret.push_str(&payee.to_string());
         }
@@ -49,7 +44,7 @@ impl ToString for Transaction {
         ret.push_str(&self.ws_last);
         ret.push_str(&self.inline_comment);
         ret.push_str(&self.eol);
-        ret
+        f.write_str(&ret)
     }
 }
 
@@ -1,83 +1,266 @@
 use std::collections::{HashMap, HashSet};
 
-use crate::{bag::Bag, types::*};
+use crate::{
+    bag::Bag,
+    check::is_same_or_child,
+    routing::Routing,
+    tolerance::{is_within, Tolerance},
+    types::*,
+};
 
-fn priority<D: Decimal>(d: &Directive<D>) -> i32 {
-    match d.content {
-        DirectiveContent::Balance(_) => 0,
-        _ => 1,
-    }
+/// How a failing balance directive is fixed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FixStrategy {
+    /// Changes the amount of the balance directive to the actual balance of the account.
+    UpdateAmount,
+    /// Inserts a pad directive from given account (e.g. `Equity:Opening-Balances`) on the day
+    /// before the balance directive, so the difference is transferred from that account.
+    Pad { source: Account },
+    /// Inserts a transaction on the day before the balance directive, that transfers the
+    /// difference from given account.
+    Adjust { source: Account },
 }
 
-/// Tries to fix the balance statements.
-pub fn fix_balance<D: Decimal>(ledger: &Ledger<D>) -> anyhow::Result<()> {
-    let mut directives: Vec<&Directive<D>> = Vec::new();
-    for (_, file) in &ledger.files {
-        for d in &file.directives {
-            directives.push(d);
-        }
-    }
-    directives.sort_by(|a, b| (a.date, priority(a)).cmp(&(b.date, priority(b))));
+/// A balance directive that was fixed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BalanceFix<D> {
+    pub date: chrono::NaiveDate,
+    pub account: Account,
+    /// The amount of the balance directive.
+    pub expected: Amount<D>,
+    /// The balance of the account, before the fix.
+    pub actual: Amount<D>,
+}
+
+/// A pad directive (from the ledger or inserted by a fix) waiting for the balance directive that
+/// uses it.
+struct Pad {
+    source: Account,
+    /// True if the pad was inserted by a fix.
+    inserted: bool,
+    /// Currencies already padded, each currency is padded only by the first balance directive.
+    padded: HashSet<Currency>,
+}
 
-    let mut failed: Vec<(&Directive<D>, &Balance<D>, D, bool)> = Vec::new();
-    let mut accounts: HashMap<Account, Bag<D>> = HashMap::new();
-    let mut padded: HashSet<Account> = HashSet::new();
-    for d in directives {
+/// Fixes the balance directives that do not match the balance of their accounts, using given
+/// strategy.
+///
+/// The balances are computed the way check does, and the fixes are applied in the canonical
+/// order, so every fix takes the previous ones into account. The inserted directives go to the
+/// files chosen by given routing, see Ledger::insert.
+///
+/// Returns the balance directives that were fixed.
+pub fn fix_balance<D: Decimal>(
+    ledger: &mut Ledger<D>,
+    strategy: &FixStrategy,
+    routing: &Routing,
+) -> crate::Result<Vec<BalanceFix<D>>, D> {
+    let tolerance = Tolerance::from_ledger(ledger)?;
+    let mut fixes: Vec<(usize, BalanceFix<D>)> = Vec::new();
+    let mut inserts: Vec<Directive<D>> = Vec::new();
+    let mut balances: HashMap<Account, Bag<D>> = HashMap::new();
+    let mut pads: HashMap<Account, Pad> = HashMap::new();
+    for (i, (_, d)) in ledger.directives().enumerate() {
         match &d.content {
-            DirectiveContent::Balance(balance) => {
-                let bag: Bag<D> = accounts.get(&balance.account).cloned().unwrap_or_default();
-                let x = bag
-                    .commodities()
-                    .get(&balance.amount.currency)
-                    .cloned()
-                    .unwrap_or_default();
-                let mut y: D = x.clone() - balance.amount.value.clone();
-                if y < 0.into() {
-                    y = -y;
+            DirectiveContent::Transaction(t) => {
+                for p in &t.postings {
+                    if let Some(units) = &p.amount {
+                        *balances.entry(p.account.clone()).or_default() += units.clone();
+                    }
                 }
-                // TODO: improve the error margin
-                let c15: D = 40.into();
-                let c1000: D = 1000.into();
-                if padded.contains(&balance.account) {
-                    let mut bag = Bag::new();
-                    bag += balance.amount.clone();
-                    accounts.insert(balance.account.clone(), bag);
-                    padded.remove(&balance.account);
+            }
+            DirectiveContent::Pad(pad) => {
+                pads.insert(
+                    pad.account.clone(),
+                    Pad {
+                        source: pad.source_account.clone(),
+                        inserted: false,
+                        padded: HashSet::new(),
+                    },
+                );
+            }
+            DirectiveContent::Balance(balance) => {
+                let expected = &balance.amount;
+                let actual: D = balances
+                    .iter()
+                    .filter(|(a, _)| is_same_or_child(a, &balance.account))
+                    .map(|(_, bag)| bag.get(&expected.currency))
+                    .fold(0.into(), |sum, v| sum + v);
+                let missing = Amount::new(
+                    expected.value.clone() - actual.clone(),
+                    expected.currency.clone(),
+                );
+                let within = is_within(&missing.value, &tolerance.for_balance(expected));
+                if within {
                     continue;
                 }
-                if y > (c15 / c1000) {
-                    failed.push((d, balance, x.clone(), false));
+                // Like in check, the pad is used only if the balance would fail.
+                let pad = pads
+                    .get_mut(&balance.account)
+                    .and_then(|pad| pad.padded.insert(expected.currency.clone()).then_some(pad));
+                if let Some(pad) = pad {
+                    transfer(&mut balances, &pad.source, &balance.account, &missing);
+                    // Only the balances padded by an inserted pad were fixed, the ones padded by
+                    // the pads of the ledger are correct.
+                    if !pad.inserted {
+                        continue;
+                    }
                 } else {
-                    for f in &mut failed {
-                        if f.1.account == balance.account {
-                            f.3 = true
+                    let day_before = d.date.pred_opt().unwrap_or(d.date);
+                    match strategy {
+                        FixStrategy::UpdateAmount => (),
+                        FixStrategy::Pad { source } => {
+                            transfer(&mut balances, source, &balance.account, &missing);
+                            // Like any pad, it also pads the other currencies of the account.
+                            pads.insert(
+                                balance.account.clone(),
+                                Pad {
+                                    source: source.clone(),
+                                    inserted: true,
+                                    padded: HashSet::from([expected.currency.clone()]),
+                                },
+                            );
+                            inserts.push(Directive::new(
+                                day_before,
+                                DirectiveContent::Pad(crate::types::Pad {
+                                    account: balance.account.clone(),
+                                    source_account: source.clone(),
+                                }),
+                            ));
+                        }
+                        FixStrategy::Adjust { source } => {
+                            transfer(&mut balances, source, &balance.account, &missing);
+                            inserts.push(
+                                Transaction::builder(day_before)
+                                    .narration("Balance adjustment")
+                                    .posting(balance.account.clone(), missing.clone())
+                                    .posting(source.clone(), -missing)
+                                    .build(),
+                            );
                         }
                     }
                 }
+                fixes.push((
+                    i,
+                    BalanceFix {
+                        date: d.date,
+                        account: balance.account.clone(),
+                        expected: expected.clone(),
+                        actual: Amount::new(actual, expected.currency.clone()),
+                    },
+                ));
             }
-            DirectiveContent::Pad(pad) => {
-                padded.insert(pad.account.clone());
-            }
-            DirectiveContent::Transaction(t) => {
-                for p in &t.postings {
-                    if let Some(a) = &p.amount {
-                        *accounts.entry(p.account.clone()).or_default() += a.clone();
-                    }
+            _ => (),
+        }
+    }
+
+    if *strategy == FixStrategy::UpdateAmount {
+        let mut fixes = fixes.iter().peekable();
+        for (i, (_, d)) in ledger.directives_mut().enumerate() {
+            if let Some((_, fix)) = fixes.next_if(|(pos, _)| *pos == i) {
+                if let DirectiveContent::Balance(balance) = &mut d.content {
+                    balance.amount = fix.actual.clone();
                 }
             }
-            _ => continue,
         }
     }
-    for f in failed {
-        println!(
-            "Balance failed for {} {}, expected {} got {} {}",
-            f.0.date,
-            f.1.account,
-            f.1.amount,
-            f.2,
-            if f.3 { "(but fixed later)" } else { "" }
+    for d in inserts {
+        ledger.insert(d, routing)?;
+    }
+    Ok(fixes.into_iter().map(|(_, fix)| fix).collect())
+}
+
+/// Moves the amount from the source account to the account.
+fn transfer<D: Decimal>(
+    balances: &mut HashMap<Account, Bag<D>>,
+    source: &Account,
+    account: &Account,
+    amount: &Amount<D>,
+) {
+    *balances.entry(account.clone()).or_default() += amount.clone();
+    *balances.entry(source.clone()).or_default() -= amount.clone();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    const INPUT: &str = r#"2020-01-01 open Assets:Cash
+2020-01-01 open Expenses:Food
+2020-01-01 open Equity:Opening-Balances
+2020-01-02 * "Coffee"
+  Expenses:Food 3 CHF
+  Assets:Cash -3 CHF
+2020-01-05 balance Assets:Cash 7 CHF
+2020-01-05 balance Assets:Cash 5 EUR
+2020-01-10 balance Assets:Cash 7 CHF
+"#;
+
+    fn fix(strategy: FixStrategy) -> (Vec<String>, String) {
+        let mut ledger: Ledger<rust_decimal::Decimal> = Ledger {
+            files: vec![("main.beancount".into(), crate::parse(INPUT).unwrap())],
+        };
+        let fixes = fix_balance(&mut ledger, &strategy, &Routing::default())
+            .unwrap()
+            .iter()
+            .map(|f| format!("{} {} {} {}", f.date, f.account, f.expected, f.actual))
+            .collect();
+        assert_eq!(
+            fix_balance(&mut ledger, &strategy, &Routing::default()).unwrap(),
+            []
+        );
+        (fixes, ledger.files[0].1.to_string())
+    }
+
+    #[test]
+    fn updates_amounts() {
+        let (fixes, output) = fix(FixStrategy::UpdateAmount);
+        assert_eq!(
+            fixes,
+            [
+                "2020-01-05 Assets:Cash 7 CHF -3 CHF",
+                "2020-01-05 Assets:Cash 5 EUR 0 EUR",
+                "2020-01-10 Assets:Cash 7 CHF -3 CHF",
+            ]
         );
+        assert!(output.ends_with(
+            "2020-01-05 balance Assets:Cash -3 CHF\n2020-01-05 balance Assets:Cash 0 EUR\n2020-01-10 balance Assets:Cash -3 CHF\n"
+        ));
     }
 
-    Ok(())
+    #[test]
+    fn inserts_single_pad_per_day() {
+        let (fixes, output) = fix(FixStrategy::Pad {
+            source: "Equity:Opening-Balances".into(),
+        });
+        assert_eq!(
+            fixes,
+            [
+                "2020-01-05 Assets:Cash 7 CHF -3 CHF",
+                "2020-01-05 Assets:Cash 5 EUR 0 EUR",
+            ]
+        );
+        assert!(output.contains(
+            "  Assets:Cash -3 CHF\n2020-01-04 pad Assets:Cash Equity:Opening-Balances\n2020-01-05 balance"
+        ));
+        assert_eq!(output.matches(" pad ").count(), 1);
+    }
+
+    #[test]
+    fn inserts_adjustment_transactions() {
+        let (fixes, output) = fix(FixStrategy::Adjust {
+            source: "Equity:Opening-Balances".into(),
+        });
+        assert_eq!(fixes.len(), 2);
+        assert!(output.contains(
+            r#"2020-01-04 * "Balance adjustment"
+  Assets:Cash 10 CHF
+  Equity:Opening-Balances -10 CHF
+2020-01-04 * "Balance adjustment"
+  Assets:Cash 5 EUR
+  Equity:Opening-Balances -5 EUR
+"#
+        ));
+    }
 }
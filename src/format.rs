@@ -1,40 +1,284 @@
-use crate::types::*;
-use itertools::Itertools;
-use std::collections::HashSet;
+use crate::{
+    tolerance::{decimal_places, last_digit},
+    types::*,
+};
+use std::{
+    collections::HashMap,
+    fmt::{Display, Formatter, Result},
+};
 
-fn sorted_hashset(h: &HashSet<String>) -> Vec<String> {
-    let mut v = h.clone().into_iter().collect::<Vec<_>>();
-    v.sort();
-    v
+/// Returns the items in the order they should be written: as they are, or sorted by given key
+/// if FormatOptions::sort is set.
+fn ordered<T, K: Ord>(
+    items: impl IntoIterator<Item = T>,
+    key: impl Fn(&T) -> K,
+    options: &FormatOptions,
+) -> Vec<T> {
+    let mut items: Vec<T> = items.into_iter().collect();
+    if options.sort {
+        items.sort_by_key(key);
+    }
+    items
+}
+
+/// Options that control how beancount files are written.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct FormatOptions {
+    /// If set, the units of postings are rounded (or padded) to the precision of their currency.
+    /// Otherwise numbers are written exactly as they are stored.
+    ///
+    /// Only the units of postings without cost and price are rounded, and only if the rounding
+    /// keeps the transaction balanced, see Transaction::quantizes. The costs, prices, and the
+    /// amounts of balance and price directives are always written exactly, as rounding them
+    /// would change what the ledger says.
+    pub display_context: Option<DisplayContext>,
+    /// If set, the tags and metadata that come from `pushtag` and `pushmeta` are written as
+    /// these pragmas again, instead of being repeated on every directive.
+    pub stack_pragmas: bool,
+    /// If set, metadata, tags and links are written in alphabetical order. Otherwise they are
+    /// written in the order they were added (for parsed files, the order of the source).
+    pub sort: bool,
+}
+
+/// Precision used when writing the numbers of each currency.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DisplayContext {
+    precisions: HashMap<Currency, u32>,
+}
+
+impl DisplayContext {
+    /// Returns new display context, without any precisions.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Learns the precision of every currency used in the ledger.
+    ///
+    /// The precision of a currency is the most common number of decimal places it is written
+    /// with in posting units, balances and prices. It can be set explicitly with the `precision`
+    /// metadata of the commodity directive, e.g. `precision: 2`.
+    pub fn from_ledger<D: Decimal>(ledger: &Ledger<D>) -> Self {
+        let mut counts: HashMap<Currency, HashMap<u32, usize>> = HashMap::new();
+        let mut explicit: HashMap<Currency, u32> = HashMap::new();
+        let mut count = |amount: &Amount<D>| {
+            *counts
+                .entry(amount.currency.clone())
+                .or_default()
+                .entry(decimal_places(&amount.value))
+                .or_default() += 1;
+        };
+        for (_, file) in &ledger.files {
+            for d in &file.directives {
+                match &d.content {
+                    DirectiveContent::Balance(b) => count(&b.amount),
+                    DirectiveContent::Commodity(c) => {
+                        if let Some(MetadataValue::Number(p)) = d.metadata.get("precision") {
+                            if let Ok(p) = p.to_string().parse::<u32>() {
+                                explicit.insert(c.clone(), p);
+                            }
+                        }
+                    }
+                    DirectiveContent::Price(p) => count(&p.amount),
+                    DirectiveContent::Transaction(t) => {
+                        for p in &t.postings {
+                            match &p.amount {
+                                Some(amount) if !p.autocomputed => count(amount),
+                                _ => (),
+                            }
+                        }
+                    }
+                    _ => (),
+                }
+            }
+        }
+        let mut precisions: HashMap<Currency, u32> = counts
+            .into_iter()
+            .filter_map(|(currency, counts)| {
+                // On ties, the larger precision wins, so that no information is dropped.
+                let (precision, _) = counts.into_iter().max_by_key(|(p, c)| (*c, *p))?;
+                Some((currency, precision))
+            })
+            .collect();
+        precisions.extend(explicit);
+        Self { precisions }
+    }
+
+    /// Sets the precision of given currency.
+    pub fn set_precision(&mut self, currency: Currency, precision: u32) {
+        self.precisions.insert(currency, precision);
+    }
+
+    /// Returns the precision of given currency, if known.
+    pub fn precision(&self, currency: &Currency) -> Option<u32> {
+        self.precisions.get(currency).copied()
+    }
+}
+
+/// Types that can be written as beancount syntax with given options.
+pub trait Format {
+    fn format(&self, f: &mut Formatter<'_>, options: &FormatOptions) -> Result;
+
+    /// Returns a value that implements Display using given options.
+    fn display<'a>(&'a self, options: &'a FormatOptions) -> Formatted<'a, Self> {
+        Formatted {
+            value: self,
+            options,
+        }
+    }
 }
 
-impl<D> std::fmt::Display for BeancountFile<D>
+/// Wrapper returned by Format::display.
+pub struct Formatted<'a, T: ?Sized> {
+    value: &'a T,
+    options: &'a FormatOptions,
+}
+
+impl<T: Format + ?Sized> Display for Formatted<'_, T> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result {
+        self.value.format(f, self.options)
+    }
+}
+
+/// Returns the amount rounded (half away from zero) to the precision of its currency, if the
+/// options have one.
+fn rounded<D: Decimal>(amount: &Amount<D>, options: &FormatOptions) -> Option<D> {
+    let precision = options
+        .display_context
+        .as_ref()?
+        .precision(&amount.currency)?;
+    let half = last_digit::<D>(precision) / 2.into();
+    let value = if amount.value < D::default() {
+        amount.value.clone() - half
+    } else {
+        amount.value.clone() + half
+    };
+    // Writing the number with the precision truncates the digits after it. The truncated zero
+    // keeps the sign of the value (e.g. `-0.00`), so it is replaced by a plain zero.
+    let value: D = format!("{:.*}", precision as usize, value).parse().ok()?;
+    if value == D::default() {
+        return format!("{:.*}", precision as usize, D::default())
+            .parse()
+            .ok();
+    }
+    Some(value)
+}
+
+impl<D: Decimal> Transaction<D> {
+    /// Returns true if the units of the postings without cost and price can be rounded to the
+    /// precision of the options, which is the case if the rounding does not change the sum of
+    /// any currency, so the transaction keeps balancing (and the amounts inferred for postings
+    /// without units stay the same).
+    fn quantizes(&self, options: &FormatOptions) -> bool {
+        if options.display_context.is_none() {
+            return false;
+        }
+        let mut changes: HashMap<&Currency, D> = HashMap::new();
+        for p in &self.postings {
+            let Some(amount) = &p.amount else { continue };
+            if p.autocomputed || p.cost.is_some() || p.price.is_some() {
+                continue;
+            }
+            if let Some(rounded) = rounded(amount, options) {
+                *changes.entry(&amount.currency).or_default() += rounded - amount.value.clone();
+            }
+        }
+        changes.values().all(|change| *change == D::default())
+    }
+}
+
+macro_rules! impl_display_with_default_options {
+    ($bound:path: $($t:ident),*) => {
+        $(
+            impl<D: $bound> Display for $t<D> {
+                fn fmt(&self, f: &mut Formatter<'_>) -> Result {
+                    self.format(f, &FormatOptions::default())
+                }
+            }
+        )*
+    };
+}
+
+impl_display_with_default_options!(Decimal: BeancountFile, Directive, Posting);
+impl_display_with_default_options!(Display: Cost, MetadataValue, Amount);
+
+impl<D> Format for BeancountFile<D>
 where
-    D: std::fmt::Display,
+    D: Decimal,
 {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    fn format(&self, f: &mut Formatter<'_>, options: &FormatOptions) -> Result {
         for path in &self.includes {
             writeln!(f, "include {:?}", path)?;
         }
         for option in &self.options {
             writeln!(f, "option {:?} {:?}", option.0, option.1)?;
         }
+        let mut stack: &[Pragma<D>] = &[];
         for directive in &self.directives {
-            write!(f, "{}", directive)?;
+            if options.stack_pragmas {
+                write_pragmas(f, stack, &directive.pragmas, options)?;
+                stack = &directive.pragmas;
+            }
+            directive.format(f, options)?;
         }
+        write_pragmas(f, stack, &[], options)?;
         Ok(())
     }
 }
 
-impl<D> std::fmt::Display for Directive<D>
+/// Writes the pops and pushes that turn the stack of pragmas into the next one.
+fn write_pragmas<D: Display + PartialEq>(
+    f: &mut Formatter<'_>,
+    stack: &[Pragma<D>],
+    next: &[Pragma<D>],
+    options: &FormatOptions,
+) -> Result {
+    let common = stack.iter().zip(next).take_while(|(a, b)| a == b).count();
+    for pragma in stack[common..].iter().rev() {
+        match pragma {
+            Pragma::Tag(tag) => writeln!(f, "poptag #{}", tag)?,
+            Pragma::Meta(key, _) => writeln!(f, "popmeta {}:", key)?,
+        }
+    }
+    for pragma in &next[common..] {
+        match pragma {
+            Pragma::Tag(tag) => writeln!(f, "pushtag #{}", tag)?,
+            Pragma::Meta(key, value) => {
+                writeln!(f, "pushmeta {}: {}", key, value.display(options))?
+            }
+        }
+    }
+    Ok(())
+}
+
+impl<D> Format for Directive<D>
 where
-    D: std::fmt::Display,
+    D: Decimal,
 {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    fn format(&self, f: &mut Formatter<'_>, options: &FormatOptions) -> Result {
+        // With stack pragmas, the pushed tags and metadata are written by the pragmas instead.
+        let pushed_tag = |tag: &String| {
+            options.stack_pragmas && self.pragmas.contains(&Pragma::Tag(tag.clone()))
+        };
+        let pushed_meta = |key: &String, value: &MetadataValue<D>| {
+            options.stack_pragmas
+                && self
+                    .pragmas
+                    .iter()
+                    .rev()
+                    .find_map(|p| match p {
+                        Pragma::Meta(k, v) if k == key => Some(v),
+                        _ => None,
+                    })
+                    .is_some_and(|v| v == value)
+        };
         write!(f, "{} ", self.date.format("%Y-%m-%d"))?;
         match &self.content {
             DirectiveContent::Balance(x) => {
-                writeln!(f, "balance {} {}", x.account, x.amount)?;
+                writeln!(
+                    f,
+                    "balance {} {} {}",
+                    x.account, x.amount.value, x.amount.currency
+                )?;
             }
             DirectiveContent::Event(x) => {
                 writeln!(f, "event {:?} {:?}", x.name, x.value)?;
@@ -46,24 +290,26 @@ where
                 writeln!(f, "commodity {}", x)?;
             }
             DirectiveContent::Open(x) => {
-                let mut currencies = x
-                    .currencies
-                    .clone()
-                    .into_iter()
-                    .map(|x| x.0)
-                    .collect::<Vec<_>>();
+                let mut currencies = x.currencies.iter().map(|x| x.as_str()).collect::<Vec<_>>();
                 currencies.sort();
                 write!(f, "open {}", x.account)?;
                 if !currencies.is_empty() {
                     write!(f, " {}", currencies.join(","))?;
                 }
+                if let Some(booking_method) = &x.booking_method {
+                    write!(f, " {:?}", booking_method)?;
+                }
                 writeln!(f)?;
             }
             DirectiveContent::Pad(x) => {
                 writeln!(f, "pad {} {}", x.account, x.source_account)?;
             }
             DirectiveContent::Price(x) => {
-                writeln!(f, "price {} {}", x.currency, x.amount)?;
+                writeln!(
+                    f,
+                    "price {} {} {}",
+                    x.currency, x.amount.value, x.amount.currency
+                )?;
             }
             DirectiveContent::Transaction(t) => {
                 write!(f, "{}", t.flag.unwrap_or('*'))?;
@@ -73,67 +319,139 @@ where
                 if let Some(narration) = &t.narration {
                     write!(f, " {:?}", narration)?;
                 }
-                for tag in sorted_hashset(&t.tags) {
+                for tag in ordered(&t.tags, |t| *t, options) {
+                    if pushed_tag(tag) {
+                        continue;
+                    }
                     write!(f, " #{}", tag)?;
                 }
-                for link in sorted_hashset(&t.links) {
+                for link in ordered(&t.links, |l| *l, options) {
                     write!(f, " ^{}", link)?;
                 }
                 writeln!(f)?;
             }
         };
-        for (key, value) in self.metadata.iter().sorted_by_key(|x| x.0) {
-            writeln!(f, "  {}: {}", key, value)?;
+        for (key, value) in ordered(&self.metadata, |(k, _)| *k, options) {
+            if pushed_meta(key, value) {
+                continue;
+            }
+            writeln!(f, "  {}: {}", key, value.display(options))?;
         }
         if let DirectiveContent::Transaction(t) = &self.content {
+            let quantize = t.quantizes(options);
+            let mut previous: Option<&Posting<D>> = None;
             for posting in &t.postings {
-                write!(f, "{}", posting)?;
+                // A posting without amount that was split into many currencies is written once.
+                let split = previous.is_some_and(|p| {
+                    p.autocomputed && posting.autocomputed && p.account == posting.account
+                });
+                if !split {
+                    posting.format_units(f, options, quantize)?;
+                }
+                previous = Some(posting);
             }
         }
         Ok(())
     }
 }
 
-impl<D> std::fmt::Display for Posting<D>
+impl<D> Format for Posting<D>
 where
-    D: std::fmt::Display,
+    D: Decimal,
 {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    /// Without its transaction, the units of the posting are written exactly.
+    fn format(&self, f: &mut Formatter<'_>, options: &FormatOptions) -> Result {
+        self.format_units(f, options, false)
+    }
+}
+
+impl<D: Decimal> Posting<D> {
+    /// Writes the posting, with the units rounded to the precision of the options if quantize is
+    /// set and the posting has no cost and price.
+    fn format_units(
+        &self,
+        f: &mut Formatter<'_>,
+        options: &FormatOptions,
+        quantize: bool,
+    ) -> Result {
         match self.flag {
             Some(c) => write!(f, "  {} {}", c, self.account)?,
             None => write!(f, "  {}", self.account)?,
         }
         if let Some(amount) = &self.amount {
             if !self.autocomputed {
-                write!(f, " {}", amount)?;
+                let quantize = quantize && self.cost.is_none() && self.price.is_none();
+                match quantize.then(|| rounded(amount, options)).flatten() {
+                    Some(value) => write!(f, " {} {}", value, amount.currency)?,
+                    None => write!(f, " {} {}", amount.value, amount.currency)?,
+                }
             }
         }
+        // The cost and price are written exactly, as rounding them would change the weight of
+        // the posting, and the transaction would no longer balance.
+        if let Some(cost) = &self.cost {
+            write!(f, " {}", cost.display(options))?;
+        }
         match &self.price {
-            Some(PostingPrice::Unit(amount)) => write!(f, " @ {}", amount)?,
-            Some(PostingPrice::Total(amount)) => write!(f, " @@ {}", amount)?,
-            None => (),
-        };
-        match &self.cost {
-            Some(cost) => {
-                if let Some(amount) = &cost.amount {
-                    write!(f, " {{{}}}", amount)?;
-                }
-            }
+            Some(PostingPrice::Unit(a)) => write!(f, " @ {} {}", a.value, a.currency)?,
+            Some(PostingPrice::Total(a)) => write!(f, " @@ {} {}", a.value, a.currency)?,
             None => (),
         };
         writeln!(f)?;
-        for (key, value) in self.metadata.iter().sorted_by_key(|x| x.0) {
-            writeln!(f, "    {}: {}", key, value)?;
+        for (key, value) in ordered(&self.metadata, |(k, _)| *k, options) {
+            writeln!(f, "    {}: {}", key, value.display(options))?;
         }
         Ok(())
     }
 }
 
-impl<D> std::fmt::Display for MetadataValue<D>
+impl<D> Format for Cost<D>
+where
+    D: Display,
+{
+    /// The numbers of the cost are always written exactly, regardless of the display context.
+    fn format(&self, f: &mut Formatter<'_>, _options: &FormatOptions) -> Result {
+        let number = |value: &D| value.to_string();
+        let mut parts: Vec<String> = Vec::new();
+        let mut number = match (&self.number_per, &self.number_total) {
+            (Some(per), Some(total)) => format!("{} # {}", number(per), number(total)),
+            (Some(per), None) => number(per),
+            (None, Some(total)) => number(total),
+            (None, None) => String::new(),
+        };
+        if let Some(currency) = &self.currency {
+            if !number.is_empty() {
+                number.push(' ');
+            }
+            number.push_str(currency.as_str());
+        }
+        if !number.is_empty() {
+            parts.push(number);
+        }
+        if let Some(date) = &self.date {
+            parts.push(date.format("%Y-%m-%d").to_string());
+        }
+        if let Some(label) = &self.label {
+            parts.push(format!("{:?}", label));
+        }
+        if self.merge {
+            parts.push("*".into());
+        }
+        // Only the total cost on its own uses the double braces syntax, when combined with the per
+        // unit cost the `#` separator is used instead.
+        if self.number_per.is_none() && self.number_total.is_some() {
+            write!(f, "{{{{{}}}}}", parts.join(", "))
+        } else {
+            write!(f, "{{{}}}", parts.join(", "))
+        }
+    }
+}
+
+impl<D> Format for MetadataValue<D>
 where
-    D: std::fmt::Display,
+    D: Display,
 {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    fn format(&self, f: &mut Formatter<'_>, _options: &FormatOptions) -> Result {
         match self {
             MetadataValue::String(x) => write!(f, "{:?}", x),
             MetadataValue::Number(x) => write!(f, "{}", x),
@@ -142,23 +460,292 @@ where
     }
 }
 
-impl std::fmt::Display for Account {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{}", self.0)
+impl<D> Format for Amount<D>
+where
+    D: Display,
+{
+    /// The amount is written exactly, only the units of postings are rounded, see
+    /// FormatOptions::display_context.
+    fn format(&self, f: &mut Formatter<'_>, _options: &FormatOptions) -> Result {
+        write!(f, "{} {}", self.value, self.currency)
     }
 }
 
-impl std::fmt::Display for Currency {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{}", self.0)
+impl Display for Account {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result {
+        write!(f, "{}", self.as_str())
     }
 }
 
-impl<D> std::fmt::Display for Amount<D>
-where
-    D: std::fmt::Display,
-{
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{} {}", self.value, self.currency)
+impl Display for Currency {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+    use rust_decimal::Decimal;
+
+    fn d(s: &str) -> Decimal {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn cost_display() {
+        let cases: Vec<(Cost<Decimal>, &str)> = vec![
+            (Cost::default(), "{}"),
+            (
+                Cost {
+                    merge: true,
+                    ..Default::default()
+                },
+                "{*}",
+            ),
+            (
+                Cost {
+                    currency: Some("USD".into()),
+                    ..Default::default()
+                },
+                "{USD}",
+            ),
+            (
+                Cost::per_unit(Amount {
+                    value: d("1.00"),
+                    currency: "USD".into(),
+                }),
+                "{1.00 USD}",
+            ),
+            (
+                Cost::total(Amount {
+                    value: d("10.00"),
+                    currency: "USD".into(),
+                }),
+                "{{10.00 USD}}",
+            ),
+            (
+                Cost {
+                    number_per: Some(d("1.00")),
+                    number_total: Some(d("5.00")),
+                    currency: Some("USD".into()),
+                    ..Default::default()
+                },
+                "{1.00 # 5.00 USD}",
+            ),
+            (
+                Cost {
+                    number_per: Some(d("1.00")),
+                    currency: Some("USD".into()),
+                    date: chrono::NaiveDate::from_ymd_opt(2020, 1, 2),
+                    label: Some("lot-a".into()),
+                    ..Default::default()
+                },
+                "{1.00 USD, 2020-01-02, \"lot-a\"}",
+            ),
+        ];
+        for (cost, expected) in cases {
+            assert_eq!(cost.to_string(), expected);
+        }
+    }
+
+    #[test]
+    fn posting_with_cost_and_price_round_trips() {
+        let input = r#"2020-01-02 * "Buy"
+  Assets:Stock 10 HOOL {1.00 USD, 2020-01-01} @ 1.10 USD
+  Assets:Cash -10.00 USD
+"#;
+        let file = crate::parse(input).unwrap();
+        assert_eq!(file.to_string(), input);
+    }
+
+    #[test]
+    fn posting_split_into_many_currencies_is_written_once() {
+        let input = r#"2020-01-02 * "Transfer"
+  Assets:Cash -10.00 CHF
+  Assets:Wallet -5.00 USD
+  Assets:Bank
+"#;
+        let file = crate::parse(input).unwrap();
+        let t = file.directives[0].content.transaction_opt().unwrap();
+        assert_eq!(t.postings.len(), 4);
+        assert_eq!(file.to_string(), input);
+    }
+
+    #[test]
+    fn display_context_quantizes_numbers() {
+        let input = r#"2020-01-01 commodity HOOL
+  precision: 0
+2020-01-02 * "Buy"
+  Assets:Stock 10.0 HOOL {33.3333333 USD}
+  Assets:Cash -333.33 USD
+2020-01-03 * "Sell"
+  Assets:Stock -10 HOOL {33.33 USD}
+  Assets:Cash 333.3 USD
+"#;
+        let ledger = Ledger {
+            files: vec![("main.beancount".into(), crate::parse(input).unwrap())],
+        };
+        let ctx = DisplayContext::from_ledger(&ledger);
+        assert_eq!(ctx.precision(&"USD".into()), Some(2));
+        assert_eq!(ctx.precision(&"HOOL".into()), Some(0));
+        let options = FormatOptions {
+            display_context: Some(ctx),
+            ..Default::default()
+        };
+        let file = &ledger.files[0].1;
+        assert_eq!(
+            file.display(&options).to_string(),
+            r#"2020-01-01 commodity HOOL
+  precision: 0
+2020-01-02 * "Buy"
+  Assets:Stock 10.0 HOOL {33.3333333 USD}
+  Assets:Cash -333.33 USD
+2020-01-03 * "Sell"
+  Assets:Stock -10 HOOL {33.33 USD}
+  Assets:Cash 333.30 USD
+"#
+        );
+        // The written transactions still balance.
+        let written = crate::parse(&file.display(&options).to_string()).unwrap();
+        for d in &written.directives {
+            if let Some(t) = d.content.transaction_opt() {
+                let res = crate::booking::interpolate(&t.postings, &Default::default());
+                assert!(res.is_ok(), "{:?}", res);
+            }
+        }
+        // Without display context, the numbers are kept as they were.
+        assert_eq!(file.display(&FormatOptions::default()).to_string(), input);
+    }
+
+    #[test]
+    fn quantizes_only_units_that_keep_ledger_correct() {
+        let input = r#"2020-01-01 open Assets:Cash
+2020-01-01 open Assets:Stock
+2020-01-01 open Expenses:Food
+2020-01-01 commodity CHF
+  precision: 2
+2020-01-01 commodity HOOL
+  precision: 0
+2020-01-01 commodity USD
+  precision: 2
+2020-01-02 * "Buy"
+  Assets:Stock 10.5 HOOL {10 USD}
+  Assets:Cash -105 USD
+2020-01-02 * "Split"
+  Expenses:Food 0.333 CHF
+  Expenses:Food 0.333 CHF
+  Assets:Cash -0.666 CHF
+2020-01-02 price EUR 1.0832 USD
+2020-01-03 balance Assets:Cash -0.666 CHF
+2020-01-03 balance Assets:Stock 10.5 HOOL
+2020-01-03 * "Shop"
+  Expenses:Food 3.004 CHF
+  Assets:Cash -3.004 CHF
+2020-01-03 * "Shop"
+  Expenses:Food 5.1 CHF
+  Assets:Cash
+"#;
+        let ledger = Ledger {
+            files: vec![("main.beancount".into(), crate::parse(input).unwrap())],
+        };
+        let options = FormatOptions {
+            display_context: Some(DisplayContext::from_ledger(&ledger)),
+            ..Default::default()
+        };
+        let output = ledger.files[0].1.display(&options).to_string();
+        // The units with cost, the transaction that would no longer balance, the price and the
+        // balance directives are written exactly.
+        assert!(output.contains("  Assets:Stock 10.5 HOOL {10 USD}\n  Assets:Cash -105.00 USD\n"));
+        assert!(output.contains("  Expenses:Food 0.333 CHF\n  Expenses:Food 0.333 CHF\n"));
+        assert!(output.contains("2020-01-02 price EUR 1.0832 USD\n"));
+        assert!(output.contains("2020-01-03 balance Assets:Cash -0.666 CHF\n"));
+        assert!(output.contains("  Expenses:Food 3.00 CHF\n  Assets:Cash -3.00 CHF\n"));
+        assert!(output.contains("  Expenses:Food 5.10 CHF\n  Assets:Cash\n"));
+        let written = Ledger {
+            files: vec![("main.beancount".into(), crate::parse(&output).unwrap())],
+        };
+        let errors: Vec<String> = crate::check(&written)
+            .iter()
+            .filter(|d| d.is_error())
+            .map(|d| d.to_string())
+            .collect();
+        assert_eq!(errors, Vec::<String>::new());
+    }
+
+    const PRAGMAS: &str = r#"pushtag #trip
+pushmeta trip: "Japan"
+2023-01-02 * "Sushi"
+  Assets:Cash -10 JPY
+  Expenses:Food
+popmeta trip:
+2023-01-03 price JPY 0.0061 CHF
+poptag #trip
+2023-01-04 * "Home"
+  Assets:Cash -5 JPY
+  Expenses:Food
+"#;
+
+    #[test]
+    fn expands_stack_pragmas_by_default() {
+        let file = crate::parse(PRAGMAS).unwrap();
+        assert_eq!(
+            file.to_string(),
+            r#"2023-01-02 * "Sushi" #trip
+  trip: "Japan"
+  Assets:Cash -10 JPY
+  Expenses:Food
+2023-01-03 price JPY 0.0061 CHF
+2023-01-04 * "Home"
+  Assets:Cash -5 JPY
+  Expenses:Food
+"#
+        );
+    }
+
+    #[test]
+    fn writes_stack_pragmas_back() {
+        let file = crate::parse(PRAGMAS).unwrap();
+        let options = FormatOptions {
+            stack_pragmas: true,
+            ..Default::default()
+        };
+        assert_eq!(file.display(&options).to_string(), PRAGMAS);
+    }
+
+    const UNSORTED: &str = r#"2023-01-02 * "Sushi" #trip #alpha ^zlink ^alink
+  zkey: "1"
+  akey: "2"
+  Assets:Cash -10 JPY
+    zz: "a"
+    aa: "b"
+  Expenses:Food
+"#;
+
+    #[test]
+    fn keeps_order_of_metadata_tags_and_links() {
+        let file = crate::parse(UNSORTED).unwrap();
+        assert_eq!(file.to_string(), UNSORTED);
+    }
+
+    #[test]
+    fn sorts_metadata_tags_and_links() {
+        let file = crate::parse(UNSORTED).unwrap();
+        let options = FormatOptions {
+            sort: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            file.display(&options).to_string(),
+            r#"2023-01-02 * "Sushi" #alpha #trip ^alink ^zlink
+  akey: "2"
+  zkey: "1"
+  Assets:Cash -10 JPY
+    aa: "b"
+    zz: "a"
+  Expenses:Food
+"#
+        );
     }
 }
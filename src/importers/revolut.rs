@@ -10,25 +10,31 @@
 //! 7. Repeat for every account (currenc)
 //! 8. Download all statements from cloud storage locally and run the importer.
 //!
+//! The imported directives are returned as a detached file, which is not part of any ledger. They
+//! can be added to the ledger with Ledger::insert, which places them according to given routing
+//! rules.
+//!
 //! Sample use:
 //!
-//! fn main() {
+//! async fn main() {
 //!   let mut bank = PathBuf::from(std::env::var("HOME").unwrap());
+//!   let mut ledger = Ledger::read(bank.join("main.beancount"), tokio::fs::read_to_string).await?;
+//!   let routing = Routing { routes: vec![Route::new("revolut/{year}.beancount").account_prefix("Assets:CH:Revolut")] };
 //!   let mut revolut = bank.clone();
 //!   revolut.push("revolut");
 //!   for file in std::fs::read_dir(revolut)? {
 //!       let imported = revolut::import(&file?.path(), "Assets:CH:Revolut", "Expenses:CH:Revolut:Fees")?;
-//!       println!("{}", imported);
+//!       for d in imported.directives {
+//!           ledger.insert(d, &routing)?;
+//!       }
 //!   }
+//!   ledger.write(tokio::fs::write).await?;
 //! }
-use crate::types::Currency;
-use crate::types::{
-    Account, Amount, BeancountFile, Directive, DirectiveContent, Posting, Transaction,
-};
+use crate::types::{Account, Amount, BeancountFile, Currency, Transaction};
 use rust_decimal::prelude::*;
 use rust_decimal::Decimal;
 use serde::Deserialize;
-use std::{collections::HashSet, path::Path};
+use std::path::Path;
 
 #[derive(Deserialize)]
 pub struct Record {
@@ -58,18 +64,22 @@ pub fn import(
     path: &Path,
     account_prefix: &str,
     fee_account: &str,
-) -> anyhow::Result<BeancountFile<Decimal>> {
+) -> crate::Result<BeancountFile<Decimal>> {
+    let error = |source: Box<dyn std::error::Error + Send + Sync>| crate::Error::Import {
+        path: path.into(),
+        source,
+    };
     let mut file = BeancountFile::default();
-    let mut rdr = csv::Reader::from_path(path)?;
+    let mut rdr = csv::Reader::from_path(path).map_err(|e| error(e.into()))?;
     for record in rdr.deserialize() {
-        let record: Record = record?;
+        let record: Record = record.map_err(|e| error(e.into()))?;
         if record.state != "COMPLETED" {
             continue;
         }
-        let currency = Currency(record.currency.clone());
-        let account = Account(format!("{}:{}", account_prefix, record.currency));
-        let expenses_account = Account(fee_account.into());
-        let (date, _) = chrono::NaiveDate::parse_and_remainder(&record.start_date, "%Y-%m-%d")?;
+        let currency = Currency::new(&record.currency);
+        let account = Account::from(format!("{}:{}", account_prefix, record.currency));
+        let (date, _) = chrono::NaiveDate::parse_and_remainder(&record.start_date, "%Y-%m-%d")
+            .map_err(|e| error(e.into()))?;
         let id = format!(
             "id-revolut-{:x}",
             md5::compute(format!(
@@ -82,47 +92,16 @@ pub fn import(
             ))
         );
 
-        let mut postings = vec![Posting {
-            account,
-            amount: Some(Amount {
-                value: Decimal::from_str(&record.amount).unwrap()
-                    - Decimal::from_str(&record.fee).unwrap(),
-                currency: currency.clone(),
-            }),
-            flag: None,
-            cost: None,
-            price: None,
-            metadata: Default::default(),
-            autocomputed: false,
-        }];
+        let amount = Decimal::from_str(&record.amount).unwrap();
+        let fee = Decimal::from_str(&record.fee).unwrap();
+        let mut transaction = Transaction::builder(date)
+            .narration(record.description)
+            .link(id)
+            .posting(account, Amount::new(amount - fee, currency.clone()));
         if record.fee != "0.00" {
-            postings.push(Posting {
-                account: expenses_account,
-                flag: None,
-                amount: Some(Amount {
-                    value: Decimal::from_str(&record.fee).unwrap(),
-                    currency: currency.clone(),
-                }),
-                cost: None,
-                price: None,
-                metadata: Default::default(),
-                autocomputed: false,
-            });
+            transaction = transaction.posting(fee_account, Amount::new(fee, currency));
         }
-
-        file.directives.push(Directive {
-            date,
-            content: DirectiveContent::Transaction(Transaction {
-                narration: Some(record.description),
-                links: HashSet::from([id.clone()]),
-                postings,
-                flag: None,
-                payee: None,
-                tags: Default::default(),
-                balanced: false,
-            }),
-            metadata: Default::default(),
-        });
+        file.directives.push(transaction.build());
     }
     Ok(file)
 }
@@ -0,0 +1,519 @@
+use crate::{
+    bag::{Bag, Conversion},
+    prices::PriceDb,
+    types::*,
+    Error,
+};
+use std::str::FromStr;
+
+/// Method used to match a reduction against the lots held in an account.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub enum Booking {
+    /// The reduction has to match exactly one lot, unless it reduces all matching lots.
+    #[default]
+    Strict,
+    /// The oldest matching lots are reduced first.
+    Fifo,
+    /// The newest matching lots are reduced first.
+    Lifo,
+    /// All lots are merged into one at average cost before reducing.
+    Average,
+    /// No matching is done, reductions are simply added to the inventory.
+    None,
+}
+
+impl FromStr for Booking {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s {
+            "STRICT" => Booking::Strict,
+            "FIFO" => Booking::Fifo,
+            "LIFO" => Booking::Lifo,
+            "AVERAGE" => Booking::Average,
+            "NONE" => Booking::None,
+            _ => return Err(Error::UnknownBookingMethod(s.to_string())),
+        })
+    }
+}
+
+/// Cost basis of units held at cost.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Lot<D> {
+    /// Cost of a single unit.
+    pub cost: Amount<D>,
+    pub date: chrono::NaiveDate,
+    pub label: Option<String>,
+}
+
+impl<D> Lot<D>
+where
+    D: Decimal,
+{
+    /// Returns true if the lot matches given (possibly partial) cost spec.
+    pub fn matches(&self, spec: &Cost<D>, units: &D) -> bool {
+        if let Some(currency) = &spec.currency {
+            if *currency != self.cost.currency {
+                return false;
+            }
+        }
+        if let Some(cost) = spec.unit_cost(units) {
+            if cost.value != self.cost.value {
+                return false;
+            }
+        }
+        if let Some(date) = &spec.date {
+            if *date != self.date {
+                return false;
+            }
+        }
+        if let Some(label) = &spec.label {
+            if Some(label) != self.label.as_ref() {
+                return false;
+            }
+        }
+        true
+    }
+}
+
+/// Units of a single commodity, optionally held at cost.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Position<D> {
+    pub units: Amount<D>,
+    pub lot: Option<Lot<D>>,
+}
+
+/// Inventory of positions held in an account.
+///
+/// Contrary to Bag, it keeps track of every lot separately, which allows matching reductions
+/// against the lots they reduce.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Inventory<D> {
+    positions: Vec<Position<D>>,
+}
+
+impl<D> Inventory<D>
+where
+    D: Decimal,
+{
+    /// Returns new empty inventory.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns all positions, in the order they were added.
+    pub fn positions(&self) -> &[Position<D>] {
+        &self.positions
+    }
+
+    /// Returns true if the inventory holds no units.
+    pub fn is_empty(&self) -> bool {
+        self.positions.is_empty()
+    }
+
+    /// Returns the units held in the inventory, ignoring the cost.
+    pub fn units(&self) -> Bag<D> {
+        let mut bag = Bag::new();
+        for p in &self.positions {
+            bag += p.units.clone();
+        }
+        bag
+    }
+
+    /// Returns the cost of the positions held at cost, plus the units of the positions without
+    /// cost.
+    pub fn cost(&self) -> Bag<D> {
+        let mut bag = Bag::new();
+        for p in &self.positions {
+            match &p.lot {
+                Some(lot) => {
+                    bag += Amount {
+                        value: p.units.value.clone() * lot.cost.value.clone(),
+                        currency: lot.cost.currency.clone(),
+                    }
+                }
+                None => bag += p.units.clone(),
+            }
+        }
+        bag
+    }
+
+    /// Returns the market value of the units in the target currency, see Bag::convert.
+    pub fn convert(
+        &self,
+        prices: &PriceDb<D>,
+        target: &Currency,
+        date: chrono::NaiveDate,
+    ) -> Conversion<D> {
+        self.units().convert(prices, target, date)
+    }
+
+    /// Returns the value of the inventory at cost in the target currency.
+    ///
+    /// The cost (or the units of positions without cost) is converted with the prices on given
+    /// date, if it is not in the target currency already.
+    pub fn convert_at_cost(
+        &self,
+        prices: &PriceDb<D>,
+        target: &Currency,
+        date: chrono::NaiveDate,
+    ) -> Conversion<D> {
+        self.cost().convert(prices, target, date)
+    }
+
+    /// Adds units to the position with the same commodity and lot, creating it if needed.
+    ///
+    /// Positions that reach zero units are removed.
+    pub fn add(&mut self, units: Amount<D>, lot: Option<Lot<D>>) {
+        let zero: D = 0.into();
+        match self
+            .positions
+            .iter_mut()
+            .position(|p| p.units.currency == units.currency && p.lot == lot)
+        {
+            Some(i) => {
+                self.positions[i].units.value += units.value;
+                if self.positions[i].units.value == zero {
+                    self.positions.remove(i);
+                }
+            }
+            None => {
+                if units.value != zero {
+                    self.positions.push(Position { units, lot });
+                }
+            }
+        }
+    }
+
+    /// Returns true if adding given units would reduce existing positions held at cost.
+    pub fn is_reduction(&self, units: &Amount<D>) -> bool {
+        let zero: D = 0.into();
+        self.positions.iter().any(|p| {
+            p.lot.is_some()
+                && p.units.currency == units.currency
+                && (p.units.value < zero) != (units.value < zero)
+        })
+    }
+
+    /// Matches a reduction against the lots of the inventory, using given booking method.
+    ///
+    /// Returns the positions to add to the inventory (with negated units) to perform the
+    /// reduction. The inventory itself is not modified.
+    pub fn match_reduction(
+        &self,
+        units: &Amount<D>,
+        spec: &Cost<D>,
+        booking: Booking,
+    ) -> crate::Result<Vec<Position<D>>, D> {
+        let zero: D = 0.into();
+        if booking == Booking::None {
+            return Err(Error::NoneBookingMethod);
+        }
+        if booking == Booking::Average || spec.merge {
+            let lot = self
+                .average_lot(&units.currency)
+                .ok_or_else(|| Error::NoMatchingLot {
+                    currency: units.currency.clone(),
+                    spec: Box::new(spec.clone()),
+                })?;
+            let available = self
+                .positions
+                .iter()
+                .filter(|p| p.units.currency == units.currency && p.lot.is_some())
+                .fold(D::default(), |acc, p| acc + p.units.value.clone());
+            if abs(&available) < abs(&units.value) {
+                return Err(Error::NotEnoughUnits {
+                    currency: units.currency.clone(),
+                    spec: Box::new(spec.clone()),
+                    requested: abs(&units.value),
+                    available: abs(&available),
+                });
+            }
+            return Ok(vec![Position {
+                units: units.clone(),
+                lot: Some(lot),
+            }]);
+        }
+        let mut matches: Vec<&Position<D>> = self
+            .positions
+            .iter()
+            .filter(|p| {
+                p.units.currency == units.currency
+                    && (p.units.value < zero) != (units.value < zero)
+                    && p.lot
+                        .as_ref()
+                        .is_some_and(|l| l.matches(spec, &units.value))
+            })
+            .collect();
+        let available = matches
+            .iter()
+            .fold(D::default(), |acc, p| acc + p.units.value.clone());
+        if matches.is_empty() {
+            return Err(Error::NoMatchingLot {
+                currency: units.currency.clone(),
+                spec: Box::new(spec.clone()),
+            });
+        }
+        if abs(&available) < abs(&units.value) {
+            return Err(Error::NotEnoughUnits {
+                currency: units.currency.clone(),
+                spec: Box::new(spec.clone()),
+                requested: abs(&units.value),
+                available: abs(&available),
+            });
+        }
+        match booking {
+            Booking::Strict => {
+                if matches.len() > 1 && available != -units.value.clone() {
+                    return Err(Error::AmbiguousReduction {
+                        units: units.clone(),
+                        spec: Box::new(spec.clone()),
+                        lots: matches.iter().filter_map(|p| p.lot.clone()).collect(),
+                    });
+                }
+            }
+            Booking::Fifo => matches.sort_by_key(|p| p.lot.as_ref().map(|l| l.date)),
+            Booking::Lifo => {
+                matches.sort_by_key(|p| std::cmp::Reverse(p.lot.as_ref().map(|l| l.date)))
+            }
+            Booking::Average | Booking::None => unreachable!("handled above"),
+        }
+        let mut remaining = units.value.clone();
+        let mut res = Vec::new();
+        for p in matches {
+            if remaining == zero {
+                break;
+            }
+            let take = if abs(&p.units.value) < abs(&remaining) {
+                -p.units.value.clone()
+            } else {
+                remaining.clone()
+            };
+            remaining = remaining - take.clone();
+            res.push(Position {
+                units: Amount {
+                    value: take,
+                    currency: units.currency.clone(),
+                },
+                lot: p.lot.clone(),
+            });
+        }
+        Ok(res)
+    }
+
+    /// Merges all lots of given commodity into a single lot at average cost.
+    pub fn average(&mut self, currency: &Currency) {
+        let lot = match self.average_lot(currency) {
+            Some(lot) => lot,
+            None => return,
+        };
+        let mut units: D = Default::default();
+        self.positions.retain(|p| {
+            if p.units.currency == *currency && p.lot.is_some() {
+                units += p.units.value.clone();
+                false
+            } else {
+                true
+            }
+        });
+        self.add(
+            Amount {
+                value: units,
+                currency: currency.clone(),
+            },
+            Some(lot),
+        );
+    }
+
+    fn average_lot(&self, currency: &Currency) -> Option<Lot<D>> {
+        let mut units: D = Default::default();
+        let mut total: D = Default::default();
+        let mut first: Option<&Lot<D>> = None;
+        for p in &self.positions {
+            let lot = match &p.lot {
+                Some(lot) if p.units.currency == *currency => lot,
+                _ => continue,
+            };
+            if let Some(f) = first {
+                if f.cost.currency != lot.cost.currency {
+                    return None;
+                }
+            }
+            if first.is_none_or(|f| lot.date < f.date) {
+                first = Some(lot);
+            }
+            units += p.units.value.clone();
+            total += p.units.value.clone() * lot.cost.value.clone();
+        }
+        let first = first?;
+        if units == 0.into() {
+            return None;
+        }
+        Some(Lot {
+            cost: Amount {
+                value: total / units,
+                currency: first.cost.currency.clone(),
+            },
+            date: first.date,
+            label: None,
+        })
+    }
+}
+
+fn abs<D: Decimal>(v: &D) -> D {
+    if *v < 0.into() {
+        -v.clone()
+    } else {
+        v.clone()
+    }
+}
+
+/// Writes the lot like a cost spec, e.g. `{100 USD, 2020-01-02}`.
+impl<D: Decimal> std::fmt::Display for Lot<D> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.label {
+            Some(label) => write!(f, "{{{}, {}, {:?}}}", self.cost, self.date, label),
+            None => write!(f, "{{{}, {}}}", self.cost, self.date),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+    use rust_decimal::Decimal;
+
+    fn amount(value: i32, currency: &str) -> Amount<Decimal> {
+        Amount {
+            value: value.into(),
+            currency: currency.into(),
+        }
+    }
+
+    fn lot(cost: i32, day: u32) -> Option<Lot<Decimal>> {
+        Some(Lot {
+            cost: amount(cost, "USD"),
+            date: chrono::NaiveDate::from_ymd_opt(2020, 1, day).unwrap(),
+            label: None,
+        })
+    }
+
+    fn inventory() -> Inventory<Decimal> {
+        let mut inv = Inventory::new();
+        inv.add(amount(10, "HOOL"), lot(100, 1));
+        inv.add(amount(10, "HOOL"), lot(120, 2));
+        inv
+    }
+
+    #[test]
+    fn add_merges_same_lot_and_removes_empty_positions() {
+        let mut inv = inventory();
+        inv.add(amount(5, "HOOL"), lot(100, 1));
+        assert_eq!(inv.positions()[0].units, amount(15, "HOOL"));
+        inv.add(amount(-15, "HOOL"), lot(100, 1));
+        assert_eq!(inv.positions().len(), 1);
+    }
+
+    #[test]
+    fn fifo_reduces_oldest_lots_first() {
+        let inv = inventory();
+        let res = inv
+            .match_reduction(&amount(-15, "HOOL"), &Cost::default(), Booking::Fifo)
+            .unwrap();
+        assert_eq!(
+            res,
+            vec![
+                Position {
+                    units: amount(-10, "HOOL"),
+                    lot: lot(100, 1)
+                },
+                Position {
+                    units: amount(-5, "HOOL"),
+                    lot: lot(120, 2)
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn lifo_reduces_newest_lots_first() {
+        let inv = inventory();
+        let res = inv
+            .match_reduction(&amount(-5, "HOOL"), &Cost::default(), Booking::Lifo)
+            .unwrap();
+        assert_eq!(
+            res,
+            vec![Position {
+                units: amount(-5, "HOOL"),
+                lot: lot(120, 2)
+            }]
+        );
+    }
+
+    #[test]
+    fn strict_fails_on_ambiguous_match() {
+        let inv = inventory();
+        let err = inv
+            .match_reduction(&amount(-5, "HOOL"), &Cost::default(), Booking::Strict)
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "ambiguous reduction of -5 HOOL matching {}, 2 lots match: {100 USD, 2020-01-01}, {120 USD, 2020-01-02}"
+        );
+    }
+
+    #[test]
+    fn strict_accepts_full_reduction_and_exact_match() {
+        let inv = inventory();
+        assert!(inv
+            .match_reduction(&amount(-20, "HOOL"), &Cost::default(), Booking::Strict)
+            .is_ok());
+        let spec = Cost::per_unit(amount(120, "USD"));
+        let res = inv
+            .match_reduction(&amount(-5, "HOOL"), &spec, Booking::Strict)
+            .unwrap();
+        assert_eq!(res[0].lot, lot(120, 2));
+    }
+
+    #[test]
+    fn average_merges_lots() {
+        let mut inv = inventory();
+        inv.average(&"HOOL".into());
+        assert_eq!(
+            inv.positions(),
+            &[Position {
+                units: amount(20, "HOOL"),
+                lot: lot(110, 1)
+            }]
+        );
+    }
+
+    #[test]
+    fn reduction_larger_than_inventory_fails() {
+        let inv = inventory();
+        let err = inv
+            .match_reduction(&amount(-25, "HOOL"), &Cost::default(), Booking::Fifo)
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "not enough units of HOOL matching {}: requested 25, available 20"
+        );
+    }
+
+    #[test]
+    fn converts_at_market_value_and_at_cost() {
+        let mut inv = inventory();
+        inv.add(amount(50, "USD"), None);
+        inv.add(amount(1, "EUR"), None);
+        let date = chrono::NaiveDate::from_ymd_opt(2020, 2, 1).unwrap();
+        let mut prices = PriceDb::new();
+        prices.insert(date, "HOOL".into(), &amount(130, "USD"));
+        let c = inv.convert(&prices, &"USD".into(), date);
+        assert_eq!(c.value, amount(2650, "USD"));
+        assert_eq!(c.unconverted.to_string(), "(1 EUR)");
+        let c = inv.convert_at_cost(&prices, &"USD".into(), date);
+        assert_eq!(c.value, amount(2250, "USD"));
+        assert_eq!(c.unconverted.to_string(), "(1 EUR)");
+    }
+}
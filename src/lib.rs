@@ -1,16 +1,31 @@
 pub mod bag;
+pub mod booking;
+pub mod builder;
 mod check;
 mod closing;
+pub mod diagnostic;
+pub mod documents;
+pub mod duplicates;
+mod error;
 pub mod exp;
 mod fix_balance;
 pub mod format;
 pub mod importers;
+pub mod inventory;
+pub mod lint;
 mod parse;
+pub mod prices;
+pub mod query;
+pub mod routing;
+#[cfg(feature = "serialize")]
+mod serialize;
 mod split_stock;
+pub mod tolerance;
 pub mod types;
 
-pub use check::check;
+pub use check::{check, check_with_options, CheckOptions};
 pub use closing::closing;
-pub use fix_balance::fix_balance;
+pub use error::{Error, Location, Result};
+pub use fix_balance::{fix_balance, BalanceFix, FixStrategy};
 pub use parse::parse;
 pub use split_stock::split_stock;
@@ -0,0 +1,352 @@
+//! Lints enforce conventions of a ledger, beyond its correctness verified by check.
+//!
+//! Every rule has a name and a level, which can be changed in the `beanru.toml` file:
+//!
+//! ```toml
+//! # Transactions using these accounts are imported, see the imported-id-link rule.
+//! imported-accounts = ["Assets:CH:Revolut:*"]
+//!
+//! [lints]
+//! narration-required = "deny"
+//! payee-title-case = "warn"
+//! no-non-leaf-postings = "allow"
+//!
+//! # Settings of the duplicate-transactions rule, see the duplicates module.
+//! [duplicates]
+//! days = 3
+//! ```
+//!
+//! TODO: support inline `; beanru: allow(rule)` suppressions, once the parser keeps comments.
+use crate::{
+    diagnostic::{Diagnostic, Severity},
+    duplicates::{find_duplicates, DuplicateConfig},
+    types::*,
+};
+use std::collections::{HashMap, HashSet};
+
+/// Lint rules, named in kebab-case in the config (e.g. `narration-required`).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, serde::Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Rule {
+    /// Every transaction has a non-empty narration.
+    NarrationRequired,
+    /// Every word of the payee starts with an upper case letter.
+    PayeeTitleCase,
+    /// Postings to `Expenses` accounts are positive.
+    ExpensesPositive,
+    /// Postings are only made to accounts without sub-accounts.
+    NoNonLeafPostings,
+    /// Imported transactions (using one of the `imported-accounts`) have a link matching the
+    /// `id-links` pattern of the duplicates settings (`id-*` by default), like the ones added by
+    /// the importers.
+    ImportedIdLink,
+    /// No transaction looks like a duplicate of another one, see the duplicates module.
+    DuplicateTransactions,
+}
+
+impl Rule {
+    pub const ALL: [Rule; 6] = [
+        Rule::NarrationRequired,
+        Rule::PayeeTitleCase,
+        Rule::ExpensesPositive,
+        Rule::NoNonLeafPostings,
+        Rule::ImportedIdLink,
+        Rule::DuplicateTransactions,
+    ];
+
+    /// Returns the name of the rule, as used in the config and reported in diagnostics.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Rule::NarrationRequired => "narration-required",
+            Rule::PayeeTitleCase => "payee-title-case",
+            Rule::ExpensesPositive => "expenses-positive",
+            Rule::NoNonLeafPostings => "no-non-leaf-postings",
+            Rule::ImportedIdLink => "imported-id-link",
+            Rule::DuplicateTransactions => "duplicate-transactions",
+        }
+    }
+
+    /// Returns the level of the rule when it is not configured. Only the conventions common to
+    /// most ledgers are enabled by default.
+    pub fn default_level(&self) -> Level {
+        match self {
+            Rule::NarrationRequired | Rule::NoNonLeafPostings | Rule::DuplicateTransactions => {
+                Level::Warn
+            }
+            Rule::PayeeTitleCase | Rule::ExpensesPositive | Rule::ImportedIdLink => Level::Allow,
+        }
+    }
+}
+
+/// Level of a lint rule.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Level {
+    /// The rule is disabled.
+    Allow,
+    /// Violations are reported as warnings.
+    Warn,
+    /// Violations are reported as errors.
+    Deny,
+}
+
+impl Level {
+    /// Returns the severity of the violations, None if the rule is disabled.
+    fn severity(&self) -> Option<Severity> {
+        match self {
+            Level::Allow => None,
+            Level::Warn => Some(Severity::Warning),
+            Level::Deny => Some(Severity::Error),
+        }
+    }
+}
+
+/// Configuration of the lints, see the module documentation for the format.
+#[derive(Debug, Clone, Default, PartialEq, serde::Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct LintConfig {
+    /// Levels of the rules, overriding their default level.
+    #[serde(default)]
+    pub lints: HashMap<Rule, Level>,
+    /// Patterns of the accounts of imported transactions, where `*` matches any characters.
+    #[serde(default)]
+    pub imported_accounts: Vec<String>,
+    #[serde(default)]
+    pub duplicates: DuplicateConfig,
+}
+
+impl LintConfig {
+    /// Parses the config from the content of a `beanru.toml` file.
+    pub fn from_toml(content: &str) -> crate::Result<Self> {
+        toml::from_str(content).map_err(|source| crate::Error::Config { source })
+    }
+
+    /// Returns the configured level of the rule.
+    pub fn level(&self, rule: Rule) -> Level {
+        self.lints
+            .get(&rule)
+            .copied()
+            .unwrap_or_else(|| rule.default_level())
+    }
+}
+
+/// Returns the violations of all enabled rules.
+pub fn lint<D: Decimal>(ledger: &Ledger<D>, config: &LintConfig) -> Vec<Diagnostic> {
+    let parents: HashSet<&str> = ledger
+        .directives()
+        .flat_map(|(_, d)| d.accounts())
+        .flat_map(|a| {
+            let a = a.as_str();
+            a.match_indices(':').map(move |(i, _)| &a[..i])
+        })
+        .collect();
+    let mut diagnostics = Vec::new();
+    for (path, d) in ledger.directives() {
+        let t = match d.content.transaction_opt() {
+            Some(t) => t,
+            None => continue,
+        };
+        for rule in Rule::ALL {
+            let severity = match config.level(rule).severity() {
+                Some(severity) => severity,
+                None => continue,
+            };
+            for message in violations(rule, t, config, &parents) {
+                diagnostics.push(Diagnostic {
+                    severity,
+                    code: rule.name(),
+                    message,
+                    location: Some(d.location(path)),
+                    related: Vec::new(),
+                });
+            }
+        }
+    }
+    if let Some(severity) = config.level(Rule::DuplicateTransactions).severity() {
+        for duplicate in find_duplicates(ledger, &config.duplicates) {
+            let (path, original) = duplicate.original;
+            let original = original.location(path);
+            diagnostics.push(Diagnostic {
+                severity,
+                code: Rule::DuplicateTransactions.name(),
+                message: format!(
+                    "transaction looks like a duplicate of {} ({})",
+                    original, duplicate.reason
+                ),
+                location: Some(duplicate.directive.location(duplicate.path)),
+                related: vec![original],
+            });
+        }
+    }
+    diagnostics
+}
+
+/// Returns the messages describing the violations of the rule by the transaction.
+fn violations<D: Decimal>(
+    rule: Rule,
+    t: &Transaction<D>,
+    config: &LintConfig,
+    parents: &HashSet<&str>,
+) -> Vec<String> {
+    match rule {
+        Rule::NarrationRequired => {
+            if t.narration.as_deref().unwrap_or_default().trim().is_empty() {
+                return vec!["transaction has no narration".into()];
+            }
+        }
+        Rule::PayeeTitleCase => {
+            if let Some(payee) = &t.payee {
+                let title_case = payee
+                    .split_whitespace()
+                    .all(|word| !word.starts_with(char::is_lowercase));
+                if !title_case {
+                    return vec![format!("payee {:?} is not in title case", payee)];
+                }
+            }
+        }
+        Rule::ExpensesPositive => {
+            return t
+                .postings
+                .iter()
+                .filter(|p| crate::query::matches("Expenses:*", p.account.as_str()))
+                .filter_map(|p| p.amount.as_ref().map(|a| (&p.account, a)))
+                .filter(|(_, a)| a.value < 0.into())
+                .map(|(account, a)| format!("posting to {} is negative: {}", account, a))
+                .collect();
+        }
+        Rule::NoNonLeafPostings => {
+            return t
+                .postings
+                .iter()
+                .filter(|p| parents.contains(p.account.as_str()))
+                .map(|p| format!("posting to {}, which has sub-accounts", p.account))
+                .collect();
+        }
+        Rule::ImportedIdLink => {
+            let imported = t.postings.iter().any(|p| {
+                config
+                    .imported_accounts
+                    .iter()
+                    .any(|pattern| crate::query::matches(pattern, p.account.as_str()))
+            });
+            let pattern = &config.duplicates.id_links;
+            if imported && !t.links.iter().any(|l| crate::query::matches(pattern, l)) {
+                return vec![format!(
+                    "imported transaction has no link matching {}",
+                    pattern
+                )];
+            }
+        }
+        // Duplicates are found by comparing transactions, see lint.
+        Rule::DuplicateTransactions => (),
+    }
+    Vec::new()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    fn lints(config: &str, input: &str) -> Vec<String> {
+        let ledger: Ledger<rust_decimal::Decimal> = Ledger {
+            files: vec![("main.beancount".into(), crate::parse(input).unwrap())],
+        };
+        lint(&ledger, &LintConfig::from_toml(config).unwrap())
+            .iter()
+            .map(|d| d.to_string())
+            .collect()
+    }
+
+    #[test]
+    fn reports_default_rules() {
+        let input = r#"2020-01-01 * "shop" ""
+  Expenses:Food 10 CHF
+  Expenses:Food:Lunch -10 CHF
+"#;
+        assert_eq!(
+            lints("", input),
+            [
+                "main.beancount:1: warning[narration-required]: transaction has no narration",
+                "main.beancount:1: warning[no-non-leaf-postings]: posting to Expenses:Food, which has sub-accounts",
+            ]
+        );
+    }
+
+    #[test]
+    fn uses_configured_levels() {
+        let config = r#"
+imported-accounts = ["Assets:Revolut:*"]
+
+[lints]
+narration-required = "allow"
+no-non-leaf-postings = "allow"
+payee-title-case = "deny"
+expenses-positive = "warn"
+imported-id-link = "deny"
+"#;
+        let input = r#"2020-01-01 * "shop" ""
+  Expenses:Food -10 CHF
+  Assets:Revolut:CHF 10 CHF
+2020-01-02 * "Big Shop" "" ^id-1
+  Expenses:Food 10 CHF
+  Assets:Revolut:CHF -10 CHF
+"#;
+        assert_eq!(
+            lints(config, input),
+            [
+                "main.beancount:1: error[payee-title-case]: payee \"shop\" is not in title case",
+                "main.beancount:1: warning[expenses-positive]: posting to Expenses:Food is negative: -10 CHF",
+                "main.beancount:1: error[imported-id-link]: imported transaction has no link matching id-*",
+            ]
+        );
+    }
+
+    #[test]
+    fn uses_id_links_pattern_of_duplicates() {
+        let config = r#"
+imported-accounts = ["Assets:Revolut:*"]
+
+[lints]
+imported-id-link = "warn"
+
+[duplicates]
+id-links = "revolut-*"
+"#;
+        let input = r#"2020-01-01 * "Shop" "Food" ^id-1
+  Expenses:Food 10 CHF
+  Assets:Revolut:CHF -10 CHF
+2020-01-02 * "Shop" "Food" ^revolut-2
+  Expenses:Food 20 CHF
+  Assets:Revolut:CHF -20 CHF
+"#;
+        assert_eq!(
+            lints(config, input),
+            ["main.beancount:1: warning[imported-id-link]: imported transaction has no link matching revolut-*"]
+        );
+    }
+
+    #[test]
+    fn reports_duplicates() {
+        let config = "[duplicates]\ndays = 1\n";
+        let input = r#"2020-01-01 * "Transfer"
+  Assets:Bank -100 CHF
+  Assets:Revolut 100 CHF
+2020-01-02 * "Transfer"
+  Assets:Revolut 100 CHF
+  Assets:Bank -100 CHF
+"#;
+        assert_eq!(
+            lints(config, input),
+            [
+                "main.beancount:4: warning[duplicate-transactions]: transaction looks like a duplicate of main.beancount:1 (100% similar postings)\nmain.beancount:1: note: related directive",
+            ]
+        );
+    }
+
+    #[test]
+    fn rejects_unknown_rules() {
+        let err = LintConfig::from_toml("[lints]\nunknown = \"deny\"\n").unwrap_err();
+        assert!(err.to_string().starts_with("invalid configuration"));
+    }
+}
@@ -1,6 +1,13 @@
+use beanru::documents::{check_with_documents, DirEntry};
+use beanru::format::{DisplayContext, Format, FormatOptions};
+use beanru::lint::LintConfig;
+use beanru::routing::Routing;
 use beanru::types::{Currency, Ledger};
-use clap::{Parser, Subcommand};
+use beanru::FixStrategy;
+use clap::{Parser, Subcommand, ValueEnum};
 use rust_decimal::Decimal;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
 
 /// Program for processing beancount files.
 #[derive(Parser)]
@@ -8,6 +15,28 @@ use rust_decimal::Decimal;
 struct Args {
     #[command(subcommand)]
     command: Commands,
+    #[command(flatten)]
+    write: WriteArgs,
+}
+
+/// Options used by the subcommands that write the ledger back.
+#[derive(clap::Args)]
+struct WriteArgs {
+    /// Rounds the units of postings to the common precision of their currency when writing the
+    /// ledger, where it keeps the transactions balanced.
+    ///
+    /// The precision is learned from the ledger, or from the `precision` metadata of the
+    /// commodity directive. Costs, prices and balances are always written exactly, and by
+    /// default all numbers are.
+    #[arg(long, global = true)]
+    quantize: bool,
+    /// Writes the tags and metadata that come from `pushtag` and `pushmeta` blocks as these
+    /// blocks again, instead of repeating them on every directive.
+    #[arg(long, global = true)]
+    stack_pragmas: bool,
+    /// Writes metadata, tags and links in alphabetical order, instead of the order of the source.
+    #[arg(long, global = true)]
+    sort: bool,
 }
 
 #[derive(Subcommand)]
@@ -17,11 +46,34 @@ enum Commands {
     /// It reads the beancount file, and then writes it in a standard format used by other
     /// subcommands.
     Normalize { input: String },
-    /// Checks if all transactions are properly balanced.
-    Check { input: String },
-    /// Checks all balance statements and tries to fix them.
-    /// This command is still WIP.
-    FixBalance { input: String },
+    /// Checks the transactions, the lifecycle and currencies of accounts, the balance
+    /// directives and the documents.
+    Check {
+        input: String,
+        /// Also reports commodities used without a commodity directive.
+        #[arg(long)]
+        undeclared_commodities: bool,
+        /// The config of the lint rules. By default, `beanru.toml` next to the input file is
+        /// used, if it exists.
+        #[arg(long)]
+        config: Option<PathBuf>,
+        #[arg(short, long, value_enum, default_value_t = CheckFormat::Text)]
+        format: CheckFormat,
+    },
+    /// Fixes the balance directives that do not match the balance of their accounts.
+    FixBalance {
+        /// The path to beancount file.
+        input: String,
+        #[arg(short, long, value_enum, default_value_t = FixMethod::UpdateAmount)]
+        strategy: FixMethod,
+        /// The account the difference is transferred from, used by the pad and adjust
+        /// strategies.
+        #[arg(long, default_value = "Equity:Opening-Balances")]
+        source: String,
+        /// Prints the diff of the changes, instead of writing the ledger.
+        #[arg(long)]
+        dry_run: bool,
+    },
     /// Performs stock split.
     StockSplit {
         /// The path to beancount file.
@@ -38,16 +90,115 @@ enum Commands {
         #[arg(short, long, default_value_t = 15)]
         days: i64,
     },
+    /// Exports the parsed ledger to standard output, for consumption by other tools.
+    Export {
+        /// The path to beancount file.
+        input: String,
+        #[arg(short, long, value_enum, default_value_t = ExportFormat::Json)]
+        format: ExportFormat,
+    },
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum ExportFormat {
+    /// JSON, in the shape documented in the serialize module of the library.
+    Json,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum FixMethod {
+    /// Changes the amount of the balance directive to the actual balance.
+    UpdateAmount,
+    /// Inserts a pad directive from the source account before the balance directive.
+    Pad,
+    /// Inserts a transaction from the source account before the balance directive.
+    Adjust,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum CheckFormat {
+    /// One line per problem, like compiler errors: `file:line: error[E001]: message`.
+    Text,
+    /// JSON array of the diagnostics.
+    Json,
 }
 
 async fn read_ledger(input: &str) -> anyhow::Result<Ledger<Decimal>> {
-    Ledger::read(input, |p| async { Ok(tokio::fs::read_to_string(p).await?) }).await
+    Ok(Ledger::read(input, tokio::fs::read_to_string).await?)
+}
+
+async fn read_lint_config(input: &str, config: Option<PathBuf>) -> anyhow::Result<LintConfig> {
+    let (path, required) = match config {
+        Some(path) => (path, true),
+        None => (Path::new(input).with_file_name("beanru.toml"), false),
+    };
+    match tokio::fs::read_to_string(&path).await {
+        Ok(content) => Ok(LintConfig::from_toml(&content)?),
+        Err(err) if !required && err.kind() == std::io::ErrorKind::NotFound => {
+            Ok(LintConfig::default())
+        }
+        Err(err) => {
+            Err(anyhow::Error::new(err).context(format!("failed to read {}", path.display())))
+        }
+    }
 }
 
-async fn write_ledger(ledger: Ledger<Decimal>) -> anyhow::Result<()> {
+fn format_options(ledger: &Ledger<Decimal>, args: &WriteArgs) -> FormatOptions {
+    FormatOptions {
+        display_context: args.quantize.then(|| DisplayContext::from_ledger(ledger)),
+        stack_pragmas: args.stack_pragmas,
+        sort: args.sort,
+    }
+}
+
+/// Returns the content of every file of the ledger, as it would be written.
+fn format_files(ledger: &Ledger<Decimal>, options: &FormatOptions) -> HashMap<PathBuf, String> {
     ledger
-        .write(|p, c| async { Ok(tokio::fs::write(p, c).await?) })
-        .await
+        .files
+        .iter()
+        .map(|(path, file)| (path.clone(), file.display(options).to_string()))
+        .collect()
+}
+
+/// Prints the unified diff of the files that changed, compared to their previous content.
+fn print_diff(before: &HashMap<PathBuf, String>, after: &HashMap<PathBuf, String>) {
+    let mut paths: Vec<&PathBuf> = after.keys().collect();
+    paths.sort();
+    for path in paths {
+        let old = before.get(path).map(String::as_str).unwrap_or_default();
+        let new = &after[path];
+        if old == new {
+            continue;
+        }
+        let name = path.display().to_string();
+        print!(
+            "{}",
+            similar::TextDiff::from_lines(old, new)
+                .unified_diff()
+                .header(&name, &name)
+        );
+    }
+}
+
+async fn write_ledger(ledger: Ledger<Decimal>, args: &WriteArgs) -> anyhow::Result<()> {
+    let options = format_options(&ledger, args);
+    ledger
+        .write_with_options(&options, tokio::fs::write)
+        .await?;
+    Ok(())
+}
+
+/// Lists the entries of the directory, for the checks of the documents.
+async fn read_dir(path: PathBuf) -> std::io::Result<Vec<DirEntry>> {
+    let mut dir = tokio::fs::read_dir(path).await?;
+    let mut entries = Vec::new();
+    while let Some(entry) = dir.next_entry().await? {
+        entries.push(DirEntry {
+            name: entry.file_name().to_string_lossy().into_owned(),
+            is_dir: entry.file_type().await?.is_dir(),
+        });
+    }
+    Ok(entries)
 }
 
 #[tokio::main]
@@ -57,15 +208,64 @@ async fn main() -> anyhow::Result<()> {
     match args.command {
         Commands::Normalize { input } => {
             let ledger = read_ledger(&input).await?;
-            write_ledger(ledger).await?;
+            write_ledger(ledger, &args.write).await?;
         }
-        Commands::Check { input } => {
+        Commands::Check {
+            input,
+            undeclared_commodities,
+            config,
+            format,
+        } => {
             let ledger = read_ledger(&input).await?;
-            beanru::check(&ledger)?;
+            let options = beanru::CheckOptions {
+                undeclared_commodities,
+                lints: read_lint_config(&input, config).await?,
+            };
+            let diagnostics = check_with_documents(&ledger, &options, read_dir).await;
+            match format {
+                CheckFormat::Text => {
+                    for d in &diagnostics {
+                        println!("{}", d);
+                    }
+                }
+                CheckFormat::Json => {
+                    serde_json::to_writer_pretty(std::io::stdout().lock(), &diagnostics)?;
+                    println!();
+                }
+            }
+            if diagnostics.iter().any(|d| d.is_error()) {
+                std::process::exit(1);
+            }
         }
-        Commands::FixBalance { input } => {
-            let ledger = read_ledger(&input).await?;
-            beanru::fix_balance(&ledger)?;
+        Commands::FixBalance {
+            input,
+            strategy,
+            source,
+            dry_run,
+        } => {
+            let mut ledger = read_ledger(&input).await?;
+            let options = format_options(&ledger, &args.write);
+            let before = format_files(&ledger, &options);
+            let strategy = match strategy {
+                FixMethod::UpdateAmount => FixStrategy::UpdateAmount,
+                FixMethod::Pad => FixStrategy::Pad {
+                    source: source.into(),
+                },
+                FixMethod::Adjust => FixStrategy::Adjust {
+                    source: source.into(),
+                },
+            };
+            for fix in beanru::fix_balance(&mut ledger, &strategy, &Routing::default())? {
+                eprintln!(
+                    "Fixed balance of {} on {}: expected {}, got {}",
+                    fix.account, fix.date, fix.expected, fix.actual
+                );
+            }
+            if dry_run {
+                print_diff(&before, &format_files(&ledger, &options));
+            } else {
+                write_ledger(ledger, &args.write).await?;
+            }
         }
         Commands::StockSplit {
             input,
@@ -73,13 +273,22 @@ async fn main() -> anyhow::Result<()> {
             ratio,
         } => {
             let mut ledger = read_ledger(&input).await?;
-            beanru::split_stock(&mut ledger, &Currency(commodity), ratio)?;
-            write_ledger(ledger).await?;
+            beanru::split_stock(&mut ledger, &Currency::from(commodity), ratio)?;
+            write_ledger(ledger, &args.write).await?;
         }
         Commands::Closing { input, days } => {
             let mut ledger = read_ledger(&input).await?;
-            beanru::closing(&mut ledger, days)?;
-            write_ledger(ledger).await?;
+            beanru::closing(&mut ledger, days, &Routing::default())?;
+            write_ledger(ledger, &args.write).await?;
+        }
+        Commands::Export { input, format } => {
+            let ledger = read_ledger(&input).await?;
+            match format {
+                ExportFormat::Json => {
+                    serde_json::to_writer_pretty(std::io::stdout().lock(), &ledger)?;
+                    println!();
+                }
+            }
         }
     }
     Ok(())
@@ -1,12 +1,376 @@
-use crate::types::*;
+use crate::{types::*, Error, Location};
 use beancount_parser as parser;
+use indexmap::IndexMap;
+use lazy_static::lazy_static;
+use regex::Regex;
+use std::path::Path;
 
-pub fn parse(content: &str) -> anyhow::Result<BeancountFile<rust_decimal::Decimal>> {
-    let beancount = match parser::parse::<rust_decimal::Decimal>(content) {
-        Ok(b) => b,
-        Err(err) => anyhow::bail!("failed to parse the beancount file: {:?}", err),
+pub fn parse(content: &str) -> crate::Result<BeancountFile<rust_decimal::Decimal>> {
+    parse_file(None, content, &mut Interner::default())
+}
+
+/// Parses the content of a single file and applies its stack pragmas to the directives. The
+/// accounts and currencies are interned with given interner.
+///
+/// The path is only used in the errors.
+pub(crate) fn parse_file<D: Decimal>(
+    path: Option<&Path>,
+    content: &str,
+    interner: &mut Interner,
+) -> crate::Result<BeancountFile<D>, D> {
+    if let Some((line, account)) = find_price_without_number(content) {
+        return Err(Error::MissingPriceNumber { account }.at(Location {
+            path: path.map(Path::to_path_buf).unwrap_or_default(),
+            line: Some(line),
+        }));
+    }
+    let mut file: BeancountFile<D> = parser::parse::<D>(content)
+        .map_err(|source| Error::Parse {
+            path: path.map(Path::to_path_buf),
+            source,
+        })?
+        .into();
+    apply_pragmas(&mut file, content);
+    restore_source_order(&mut file, content);
+    intern(&mut file, interner);
+    Ok(file)
+}
+
+/// Replaces the accounts and currencies of the file with the interned ones.
+fn intern<D>(file: &mut BeancountFile<D>, interner: &mut Interner) {
+    let metadata = |interner: &mut Interner, value: &mut MetadataValue<D>| {
+        if let MetadataValue::Currency(c) = value {
+            interner.currency(c);
+        }
     };
-    Ok(beancount.into())
+    for d in &mut file.directives {
+        for value in d.metadata.values_mut() {
+            metadata(interner, value);
+        }
+        for pragma in &mut d.pragmas {
+            if let Pragma::Meta(_, value) = pragma {
+                metadata(interner, value);
+            }
+        }
+        match &mut d.content {
+            DirectiveContent::Balance(b) => {
+                interner.account(&mut b.account);
+                interner.currency(&mut b.amount.currency);
+            }
+            DirectiveContent::Close(c) => interner.account(&mut c.account),
+            DirectiveContent::Commodity(c) => interner.currency(c),
+            DirectiveContent::Open(o) => {
+                interner.account(&mut o.account);
+                o.currencies = o
+                    .currencies
+                    .drain()
+                    .map(|mut c| {
+                        interner.currency(&mut c);
+                        c
+                    })
+                    .collect();
+            }
+            DirectiveContent::Pad(p) => {
+                interner.account(&mut p.account);
+                interner.account(&mut p.source_account);
+            }
+            DirectiveContent::Price(p) => {
+                interner.currency(&mut p.currency);
+                interner.currency(&mut p.amount.currency);
+            }
+            DirectiveContent::Transaction(t) => {
+                for p in &mut t.postings {
+                    interner.account(&mut p.account);
+                    if let Some(amount) = &mut p.amount {
+                        interner.currency(&mut amount.currency);
+                    }
+                    if let Some(currency) = p.cost.as_mut().and_then(|c| c.currency.as_mut()) {
+                        interner.currency(currency);
+                    }
+                    match &mut p.price {
+                        Some(PostingPrice::Unit(a)) | Some(PostingPrice::Total(a)) => {
+                            interner.currency(&mut a.currency)
+                        }
+                        None => (),
+                    }
+                    for value in p.metadata.values_mut() {
+                        metadata(interner, value);
+                    }
+                }
+            }
+            DirectiveContent::Event(_) => (),
+        }
+    }
+}
+
+/// Returns the line and account of the first posting with a price without number, like
+/// `Assets:Stock 10 HOOL @ USD`.
+///
+/// Inferring the price is not supported: the parser can not read such postings and silently
+/// drops the rest of the transaction, so they are reported instead. Lines of multi-line strings
+/// (like a narration or a metadata value) are not postings, and are skipped.
+fn find_price_without_number(content: &str) -> Option<(u32, Account)> {
+    lazy_static! {
+        static ref RE: Regex = Regex::new(
+            r#"^[ \t]+(?:[!*]\s+)?([A-Z][^\s;"]*)\s[^;"]*@@?\s*(?:[A-Z][^\s;"]*)?\s*(?:;.*)?$"#
+        )
+        .unwrap();
+    }
+    let mut in_string = false;
+    content.lines().enumerate().find_map(|(i, line)| {
+        let starts_in_string = in_string;
+        in_string = ends_in_string(line, in_string);
+        if starts_in_string {
+            return None;
+        }
+        let account = RE.captures(line)?.get(1)?.as_str();
+        Some((i as u32 + 1, Account::new(account)))
+    })
+}
+
+/// Returns true if a string is still open at the end of the line, given whether one was open at
+/// its start. Quotes escaped with `\` and quotes in comments are ignored.
+fn ends_in_string(line: &str, mut in_string: bool) -> bool {
+    let mut chars = line.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' if in_string => {
+                chars.next();
+            }
+            '"' => in_string = !in_string,
+            ';' if !in_string => break,
+            _ => (),
+        }
+    }
+    in_string
+}
+
+/// Restores the order of metadata, tags and links, as the parser keeps them in hash maps.
+///
+/// The order is found in the source of the directive. Items that are not written there (like the
+/// ones pushed by pragmas) go last, in the order of the pragmas.
+fn restore_source_order<D>(file: &mut BeancountFile<D>, content: &str) {
+    let lines: Vec<&str> = content.lines().collect();
+    for d in &mut file.directives {
+        let start = match d.line {
+            Some(line) if line > 0 && (line as usize) <= lines.len() => line as usize - 1,
+            _ => continue,
+        };
+        let (tags, links) = tags_and_links(lines[start]);
+        // Metadata keys of the directive, then of every posting.
+        let mut sections: Vec<Vec<&str>> = vec![Vec::new()];
+        for line in lines[start + 1..]
+            .iter()
+            .take_while(|l| l.starts_with([' ', '\t']))
+        {
+            let mut tokens = line.split_whitespace();
+            let first = match tokens.next() {
+                Some(first) => first,
+                None => continue,
+            };
+            if first.starts_with(|c: char| c.is_ascii_lowercase()) && first.ends_with(':') {
+                let key = first.trim_end_matches(':');
+                sections.last_mut().unwrap().push(key);
+                continue;
+            }
+            // Postings start with an account, optionally preceded by a flag.
+            let account = if first.len() == 1 {
+                tokens.next().unwrap_or_default()
+            } else {
+                first
+            };
+            if account.starts_with(|c: char| c.is_uppercase()) && account.contains(':') {
+                sections.push(Vec::new());
+            }
+        }
+
+        let pushed_tags: Vec<&str> = d
+            .pragmas
+            .iter()
+            .filter_map(|p| match p {
+                Pragma::Tag(tag) => Some(tag.as_str()),
+                _ => None,
+            })
+            .collect();
+        sort_by_source(&mut d.metadata, &sections[0]);
+        if let DirectiveContent::Transaction(t) = &mut d.content {
+            t.tags
+                .sort_by_cached_key(|tag| source_key(&tags, &pushed_tags, tag));
+            t.links
+                .sort_by_cached_key(|link| source_key(&links, &[], link));
+            let mut section = 0;
+            let mut previous: Option<&Posting<D>> = None;
+            for posting in &mut t.postings {
+                // A posting without amount that was split into many currencies is written once.
+                let split = previous.is_some_and(|p| {
+                    p.autocomputed && posting.autocomputed && p.account == posting.account
+                });
+                if !split {
+                    section += 1;
+                }
+                if let Some(keys) = sections.get(section) {
+                    sort_by_source(&mut posting.metadata, keys);
+                }
+                previous = Some(posting);
+            }
+        }
+    }
+}
+
+fn sort_by_source<V>(metadata: &mut IndexMap<String, V>, keys: &[&str]) {
+    metadata.sort_by_cached_key(|key, _| source_key(keys, &[], key));
+}
+
+/// Returns the key that orders the items by their position in the source, then in the pragmas.
+fn source_key(source: &[&str], pragmas: &[&str], item: &str) -> (usize, usize, String) {
+    match source.iter().position(|s| *s == item) {
+        Some(pos) => (0, pos, item.to_string()),
+        None => match pragmas.iter().position(|p| *p == item) {
+            Some(pos) => (1, pos, item.to_string()),
+            None => (2, 0, item.to_string()),
+        },
+    }
+}
+
+/// Returns the tags and links written in the first line of a transaction.
+fn tags_and_links(line: &str) -> (Vec<&str>, Vec<&str>) {
+    let mut tokens: Vec<&str> = Vec::new();
+    let mut in_string = false;
+    let mut escaped = false;
+    let mut token_start: Option<usize> = None;
+    for (i, c) in line.char_indices() {
+        if in_string {
+            match c {
+                '\\' if !escaped => escaped = true,
+                '"' if !escaped => in_string = false,
+                _ => escaped = false,
+            }
+            continue;
+        }
+        match c {
+            '"' => in_string = true,
+            ';' => break,
+            c if c.is_whitespace() => tokens.extend(token_start.take().map(|s| &line[s..i])),
+            _ => {
+                token_start.get_or_insert(i);
+            }
+        }
+    }
+    if !in_string {
+        tokens.extend(token_start.map(|s| &line[s..]));
+    }
+    let tags = tokens.iter().filter_map(|t| t.strip_prefix('#')).collect();
+    let links = tokens.iter().filter_map(|t| t.strip_prefix('^')).collect();
+    (tags, links)
+}
+
+/// Applies the `pushtag` and `pushmeta` pragmas to the directives between the push and the
+/// matching pop.
+///
+/// The parser already adds the pushed tags to transactions, but drops the pragmas themselves
+/// (and ignores `pushmeta` altogether), so they are found again in the source.
+fn apply_pragmas<D: Decimal>(file: &mut BeancountFile<D>, content: &str) {
+    let mut events = content
+        .lines()
+        .enumerate()
+        .filter_map(|(i, line)| Some((i as u32 + 1, parse_pragma::<D>(line)?)))
+        .peekable();
+    if events.peek().is_none() {
+        return;
+    }
+    let mut stack: Vec<Pragma<D>> = Vec::new();
+    // The directives are in the order of the file.
+    for d in &mut file.directives {
+        let line = d.line.unwrap_or_default();
+        while let Some((_, event)) = events.next_if(|(l, _)| *l < line) {
+            match event {
+                PragmaEvent::Push(pragma) => stack.push(pragma),
+                PragmaEvent::Pop(pragma) => {
+                    let pos = stack.iter().rposition(|p| match (p, &pragma) {
+                        (Pragma::Meta(a, _), Pragma::Meta(b, _)) => a == b,
+                        (a, b) => a == b,
+                    });
+                    if let Some(pos) = pos {
+                        stack.remove(pos);
+                    }
+                }
+            }
+        }
+        let mut pushed_metadata: IndexMap<String, MetadataValue<D>> = IndexMap::new();
+        for pragma in &stack {
+            match pragma {
+                Pragma::Tag(tag) => {
+                    if let DirectiveContent::Transaction(t) = &mut d.content {
+                        t.tags.insert(tag.clone());
+                    }
+                }
+                Pragma::Meta(key, value) => {
+                    pushed_metadata.insert(key.clone(), value.clone());
+                }
+            }
+        }
+        for (key, value) in pushed_metadata {
+            d.metadata.entry(key).or_insert(value);
+        }
+        d.pragmas = stack.clone();
+    }
+}
+
+enum PragmaEvent<D> {
+    Push(Pragma<D>),
+    Pop(Pragma<D>),
+}
+
+fn parse_pragma<D: Decimal>(line: &str) -> Option<PragmaEvent<D>> {
+    let (keyword, rest) = line.split_once(char::is_whitespace)?;
+    let rest = rest.trim();
+    let tag = || {
+        let tag = rest.split_whitespace().next()?.strip_prefix('#')?;
+        Some(Pragma::Tag(tag.to_string()))
+    };
+    match keyword {
+        "pushtag" => Some(PragmaEvent::Push(tag()?)),
+        "poptag" => Some(PragmaEvent::Pop(tag()?)),
+        "pushmeta" => {
+            let (key, value) = rest.split_once(':')?;
+            let value = parse_metadata_value(value.trim())?;
+            Some(PragmaEvent::Push(Pragma::Meta(
+                key.trim().to_string(),
+                value,
+            )))
+        }
+        "popmeta" => {
+            let key = rest.strip_suffix(':').unwrap_or(rest).trim();
+            Some(PragmaEvent::Pop(Pragma::Meta(
+                key.to_string(),
+                MetadataValue::String(String::new()),
+            )))
+        }
+        _ => None,
+    }
+}
+
+/// Parses the value of `pushmeta`: a string, a number or a currency. Other values (like dates
+/// or accounts) are kept as strings.
+fn parse_metadata_value<D: Decimal>(value: &str) -> Option<MetadataValue<D>> {
+    if let Some(quoted) = value.strip_prefix('"') {
+        let end = quoted.rfind('"')?;
+        return Some(MetadataValue::String(quoted[..end].replace("\\\"", "\"")));
+    }
+    let value = value.split(';').next()?.trim();
+    if value.is_empty() {
+        return None;
+    }
+    if let Ok(number) = value.parse::<D>() {
+        return Some(MetadataValue::Number(number));
+    }
+    if value
+        .chars()
+        .all(|c| c.is_ascii_uppercase() || c.is_ascii_digit() || "'._-".contains(c))
+    {
+        return Some(MetadataValue::Currency(value.into()));
+    }
+    Some(MetadataValue::String(value.to_string()))
 }
 
 impl<D> From<parser::BeancountFile<D>> for BeancountFile<D>
@@ -35,6 +399,8 @@ where
                 .into_iter()
                 .map(|(key, value)| (key.to_string(), value.into()))
                 .collect(),
+            line: Some(d.line_number),
+            pragmas: Vec::new(),
         }
     }
 }
@@ -83,6 +449,7 @@ impl From<parser::Open> for Open {
         Self {
             account: v.account.into(),
             currencies: v.currencies.into_iter().map(|c| c.into()).collect(),
+            booking_method: v.booking_method.map(|b| b.to_string()),
         }
     }
 }
@@ -106,7 +473,7 @@ impl From<parser::Close> for Close {
 
 impl From<parser::Account> for Account {
     fn from(v: parser::Account) -> Self {
-        Account(v.as_str().to_owned())
+        Account::new(v.as_str())
     }
 }
 
@@ -135,8 +502,8 @@ where
     fn from(v: parser::Transaction<D>) -> Self {
         let mut t = Self {
             flag: v.flag,
-            payee: v.payee.map(String::from),
-            narration: v.narration.map(String::from),
+            payee: v.payee,
+            narration: v.narration,
             tags: v.tags.into_iter().map(|x| x.to_string()).collect(),
             links: v.links.into_iter().map(|x| x.to_string()).collect(),
             postings: v.postings.into_iter().map(|x| x.into()).collect(),
@@ -167,11 +534,21 @@ impl<D> From<parser::Posting<D>> for Posting<D> {
     }
 }
 
+// The parser only supports per unit costs with an optional date, so the remaining parts of the
+// spec (total cost, label, merge) are never set here.
 impl<D> From<parser::Cost<D>> for Cost<D> {
     fn from(v: parser::Cost<D>) -> Self {
+        let (number_per, currency) = match v.amount {
+            Some(a) => (Some(a.value), Some(a.currency.into())),
+            None => (None, None),
+        };
         Self {
-            amount: v.amount.map(|x| x.into()),
+            number_per,
+            number_total: None,
+            currency,
             date: v.date.map(|d| from_parser_date_to_chrono_date(&d)),
+            label: None,
+            merge: false,
         }
     }
 }
@@ -200,6 +577,58 @@ impl<D> From<parser::PostingPrice<D>> for PostingPrice<D> {
 
 impl From<parser::Currency> for Currency {
     fn from(v: parser::Currency) -> Self {
-        Currency(v.as_str().to_owned())
+        Currency::new(v.as_str())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reports_price_without_number() {
+        let input = r#"2020-01-01 * "Buy"
+  Assets:Stock 10 HOOL @ USD
+  Assets:Cash -1000 USD
+2020-01-02 * "Buy"
+  Assets:Stock 10 HOOL @@
+  Assets:Cash -1000 USD
+"#;
+        let err = parse(input).unwrap_err();
+        assert!(matches!(err.kind(), Error::MissingPriceNumber { .. }));
+        assert_eq!(
+            err.to_string(),
+            ":2: price of posting to Assets:Stock has no number, inferring it is not supported"
+        );
+        assert_eq!(
+            parse(
+                &input
+                    .replace("@ USD", "@ 100 USD")
+                    .replace("@@\n", "@@ 1000 USD\n")
+            )
+            .unwrap()
+            .directives[1]
+                .content
+                .transaction_opt()
+                .unwrap()
+                .postings
+                .len(),
+            2
+        );
+    }
+
+    #[test]
+    fn ignores_prices_without_number_in_strings() {
+        let input = r#"2020-01-01 * "Buy" "Shares, booked as
+  Assets:Stock 10 HOOL @ USD
+until the price is known"
+  note: "Assets:Stock 10 HOOL @ USD"
+  Assets:Stock 10 HOOL @ 100 USD
+  Assets:Cash -1000 USD
+"#;
+        let file = parse(input).unwrap();
+        let t = file.directives[0].content.transaction_opt().unwrap();
+        assert_eq!(t.postings.len(), 2);
+        assert!(t.narration.as_ref().unwrap().contains("@ USD"));
     }
 }
@@ -0,0 +1,235 @@
+//! Database of the prices of commodities, used to compute market values.
+//!
+//! ```
+//! # use beanru::{prices::PriceDb, types::*};
+//! # fn run(ledger: &Ledger<rust_decimal::Decimal>) {
+//! let prices = PriceDb::from_ledger(ledger);
+//! let date = chrono::NaiveDate::from_ymd_opt(2023, 12, 31).unwrap();
+//! if let Some(rate) = prices.rate(&"HOOL".into(), &"CHF".into(), date) {
+//!     println!("1 HOOL = {} CHF", rate);
+//! }
+//! # }
+//! ```
+use crate::types::*;
+use chrono::NaiveDate;
+use std::collections::HashMap;
+
+/// Prices of commodities over time.
+///
+/// Every price is stored in both directions, so the inverse rate is available as well. If there is
+/// no rate between two currencies, it is computed through the base currency (if set).
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PriceDb<D> {
+    /// Rates from one currency to another, ordered by date.
+    rates: HashMap<(Currency, Currency), Vec<(NaiveDate, D)>>,
+    base: Option<Currency>,
+}
+
+impl<D> PriceDb<D>
+where
+    D: Decimal,
+{
+    /// Returns new empty price database.
+    pub fn new() -> Self {
+        Self {
+            rates: HashMap::new(),
+            base: None,
+        }
+    }
+
+    /// Returns the prices of the ledger.
+    ///
+    /// Besides the `price` directives, it includes the prices implied by the postings of
+    /// transactions: the `@` or `@@` price, or the cost if the posting has no price. The first
+    /// `operating_currency` option is used as the base currency.
+    pub fn from_ledger(ledger: &Ledger<D>) -> Self {
+        let mut db = Self::new();
+        db.base = ledger
+            .files
+            .iter()
+            .flat_map(|(_, f)| &f.options)
+            .find(|(name, _)| name == "operating_currency")
+            .map(|(_, value)| Currency::new(value.trim()));
+        for (_, d) in ledger.directives() {
+            match &d.content {
+                DirectiveContent::Price(p) => db.insert(d.date, p.currency.clone(), &p.amount),
+                DirectiveContent::Transaction(t) => {
+                    for posting in &t.postings {
+                        if let (Some(units), Some(price)) =
+                            (&posting.amount, implicit_price(posting))
+                        {
+                            db.insert(d.date, units.currency.clone(), &price);
+                        }
+                    }
+                }
+                _ => (),
+            }
+        }
+        db
+    }
+
+    /// Sets the currency used to triangulate rates between currencies without a direct price.
+    pub fn with_base(mut self, base: impl Into<Currency>) -> Self {
+        self.base = Some(base.into());
+        self
+    }
+
+    /// Adds the price of a single unit of the currency on given date.
+    ///
+    /// If there already is a price for that date, it is replaced.
+    pub fn insert(&mut self, date: NaiveDate, currency: Currency, price: &Amount<D>) {
+        let zero: D = 0.into();
+        if price.value == zero || currency == price.currency {
+            return;
+        }
+        let inverse = D::from(1) / price.value.clone();
+        self.insert_rate(
+            date,
+            currency.clone(),
+            price.currency.clone(),
+            price.value.clone(),
+        );
+        self.insert_rate(date, price.currency.clone(), currency, inverse);
+    }
+
+    fn insert_rate(&mut self, date: NaiveDate, from: Currency, to: Currency, rate: D) {
+        let rates = self.rates.entry((from, to)).or_default();
+        let pos = rates.partition_point(|(d, _)| *d <= date);
+        if pos > 0 && rates[pos - 1].0 == date {
+            rates[pos - 1].1 = rate;
+        } else {
+            rates.insert(pos, (date, rate));
+        }
+    }
+
+    /// Returns the value of a single unit of `from` in `to`, using the latest price on or before
+    /// given date.
+    ///
+    /// If there is no price between the currencies, the rate is computed through the base
+    /// currency. Returns None if the rate is not known.
+    pub fn rate(&self, from: &Currency, to: &Currency, date: NaiveDate) -> Option<D> {
+        if from == to {
+            return Some(1.into());
+        }
+        if let Some(rate) = self.direct_rate(from, to, date) {
+            return Some(rate);
+        }
+        let base = self.base.as_ref()?;
+        if base == from || base == to {
+            return None;
+        }
+        Some(self.direct_rate(from, base, date)? * self.direct_rate(base, to, date)?)
+    }
+
+    /// Returns the price of a single unit of `from` in `to`, see PriceDb::rate.
+    pub fn price(&self, from: &Currency, to: &Currency, date: NaiveDate) -> Option<Amount<D>> {
+        Some(Amount {
+            value: self.rate(from, to, date)?,
+            currency: to.clone(),
+        })
+    }
+
+    fn direct_rate(&self, from: &Currency, to: &Currency, date: NaiveDate) -> Option<D> {
+        let rates = self.rates.get(&(from.clone(), to.clone()))?;
+        let pos = rates.partition_point(|(d, _)| *d <= date);
+        if pos == 0 {
+            return None;
+        }
+        Some(rates[pos - 1].1.clone())
+    }
+}
+
+/// Returns the price of a single unit implied by the posting, from its price or cost.
+fn implicit_price<D: Decimal>(posting: &Posting<D>) -> Option<Amount<D>> {
+    let units = posting.amount.as_ref()?;
+    match &posting.price {
+        Some(PostingPrice::Unit(price)) => Some(price.clone()),
+        Some(PostingPrice::Total(price)) => {
+            if units.value == 0.into() {
+                return None;
+            }
+            Some(Amount {
+                value: price.value.clone() / units.clone().abs().value,
+                currency: price.currency.clone(),
+            })
+        }
+        None => posting.cost.as_ref()?.unit_cost(&units.value),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+    use rust_decimal::Decimal;
+
+    fn d(s: &str) -> Decimal {
+        s.parse().unwrap()
+    }
+
+    fn date(s: &str) -> NaiveDate {
+        s.parse().unwrap()
+    }
+
+    fn prices(input: &str) -> PriceDb<Decimal> {
+        PriceDb::from_ledger(&Ledger {
+            files: vec![("main.beancount".into(), crate::parse(input).unwrap())],
+        })
+    }
+
+    #[test]
+    fn looks_up_latest_price_on_or_before_date() {
+        let db = prices(
+            r#"2023-01-01 price HOOL 100 USD
+2023-02-01 price HOOL 110 USD
+"#,
+        );
+        let (hool, usd) = ("HOOL".into(), "USD".into());
+        assert_eq!(db.rate(&hool, &usd, date("2022-12-31")), None);
+        assert_eq!(db.rate(&hool, &usd, date("2023-01-01")), Some(d("100")));
+        assert_eq!(db.rate(&hool, &usd, date("2023-01-31")), Some(d("100")));
+        assert_eq!(db.rate(&hool, &usd, date("2023-02-01")), Some(d("110")));
+        assert_eq!(db.rate(&usd, &hool, date("2023-01-15")), Some(d("0.01")));
+        assert_eq!(db.rate(&usd, &usd, date("2000-01-01")), Some(d("1")));
+    }
+
+    #[test]
+    fn includes_implicit_prices_of_postings() {
+        let db = prices(
+            r#"2023-01-01 * "Exchange"
+  Assets:EUR 100 EUR @ 1.10 USD
+  Assets:USD
+2023-01-02 * "Exchange"
+  Assets:CHF 200 CHF @@ 210 USD
+  Assets:USD
+2023-01-03 * "Buy"
+  Assets:Stock 2 HOOL {500 USD}
+  Assets:USD
+"#,
+        );
+        let usd = "USD".into();
+        let at = date("2023-01-03");
+        assert_eq!(db.rate(&"EUR".into(), &usd, at), Some(d("1.10")));
+        assert_eq!(db.rate(&"CHF".into(), &usd, at), Some(d("1.05")));
+        assert_eq!(db.rate(&"HOOL".into(), &usd, at), Some(d("500")));
+    }
+
+    #[test]
+    fn triangulates_through_base_currency() {
+        let db = prices(
+            r#"option "operating_currency" "USD"
+2023-01-01 price HOOL 100 USD
+2023-01-01 price CHF 1.25 USD
+"#,
+        );
+        assert_eq!(
+            db.rate(&"HOOL".into(), &"CHF".into(), date("2023-01-01")),
+            Some(d("80"))
+        );
+        let db = db.with_base("EUR");
+        assert_eq!(
+            db.rate(&"HOOL".into(), &"CHF".into(), date("2023-01-01")),
+            None
+        );
+    }
+}
@@ -0,0 +1,267 @@
+//! Composable queries over the directives of a ledger.
+//!
+//! ```
+//! # use beanru::types::*;
+//! # fn run(ledger: &mut Ledger<rust_decimal::Decimal>) {
+//! let from = chrono::NaiveDate::from_ymd_opt(2023, 1, 1).unwrap();
+//! let to = chrono::NaiveDate::from_ymd_opt(2024, 1, 1).unwrap();
+//! for d in ledger.transactions().between(from, to).touching("Assets:CH:*").with_tag("trip") {
+//!     println!("{}", d);
+//! }
+//! for d in ledger.transactions_mut().with_payee("Migros") {
+//!     d.metadata.insert("category".into(), MetadataValue::String("food".into()));
+//! }
+//! # }
+//! ```
+use crate::types::*;
+
+type Predicate<'a, D> = Box<dyn Fn(&Directive<D>) -> bool + 'a>;
+
+/// Query over the directives of a ledger, see Ledger::query and Ledger::transactions.
+///
+/// Iterating the query returns the matching directives in the canonical order.
+pub struct Query<'a, D> {
+    ledger: &'a Ledger<D>,
+    predicates: Vec<Predicate<'a, D>>,
+}
+
+/// Same as Query, but returns the directives for modification, see Ledger::query_mut and
+/// Ledger::transactions_mut.
+pub struct QueryMut<'a, D> {
+    ledger: &'a mut Ledger<D>,
+    predicates: Vec<Predicate<'a, D>>,
+}
+
+/// Implements the filters, which are the same for both kinds of queries.
+macro_rules! impl_filters {
+    ($t:ident) => {
+        impl<'a, D: Decimal + 'a> $t<'a, D> {
+            /// Keeps only the directives matching given predicate.
+            pub fn filter(mut self, predicate: impl Fn(&Directive<D>) -> bool + 'a) -> Self {
+                self.predicates.push(Box::new(predicate));
+                self
+            }
+
+            /// Keeps only the directives dated from `from` (inclusive) to `to` (exclusive).
+            pub fn between(self, from: chrono::NaiveDate, to: chrono::NaiveDate) -> Self {
+                self.filter(move |d| from <= d.date && d.date < to)
+            }
+
+            /// Keeps only the directives that use an account matching given pattern.
+            ///
+            /// In the pattern, `*` matches any characters, so `Assets:CH:*` matches all accounts
+            /// below `Assets:CH`. Without `*`, only the exact account matches.
+            pub fn touching(self, pattern: &str) -> Self {
+                let pattern = pattern.to_string();
+                self.filter(move |d| d.accounts().iter().any(|a| matches(&pattern, a.as_str())))
+            }
+
+            /// Keeps only the transactions with given tag (without `#`).
+            pub fn with_tag(self, tag: &str) -> Self {
+                let tag = tag.to_string();
+                self.filter(move |d| transaction(d).is_some_and(|t| t.tags.contains(&tag)))
+            }
+
+            /// Keeps only the transactions with given link (without `^`).
+            pub fn with_link(self, link: &str) -> Self {
+                let link = link.to_string();
+                self.filter(move |d| transaction(d).is_some_and(|t| t.links.contains(&link)))
+            }
+
+            /// Keeps only the transactions with given payee.
+            pub fn with_payee(self, payee: &str) -> Self {
+                let payee = payee.to_string();
+                self.filter(move |d| {
+                    transaction(d).is_some_and(|t| t.payee.as_deref() == Some(payee.as_str()))
+                })
+            }
+
+            /// Keeps only the directives that have metadata with given key.
+            pub fn with_metadata_key(self, key: &str) -> Self {
+                let key = key.to_string();
+                self.filter(move |d| d.metadata.contains_key(&key))
+            }
+
+            /// Keeps only the directives that have metadata with given key and value.
+            pub fn with_metadata(self, key: &str, value: MetadataValue<D>) -> Self {
+                let key = key.to_string();
+                self.filter(move |d| d.metadata.get(&key) == Some(&value))
+            }
+        }
+    };
+}
+
+impl_filters!(Query);
+impl_filters!(QueryMut);
+
+impl<'a, D: Decimal + 'a> IntoIterator for Query<'a, D> {
+    type Item = &'a Directive<D>;
+    type IntoIter = std::vec::IntoIter<&'a Directive<D>>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        let predicates = self.predicates;
+        self.ledger
+            .directives()
+            .map(|(_, d)| d)
+            .filter(|d| predicates.iter().all(|p| p(d)))
+            .collect::<Vec<_>>()
+            .into_iter()
+    }
+}
+
+impl<'a, D: Decimal + 'a> IntoIterator for QueryMut<'a, D> {
+    type Item = &'a mut Directive<D>;
+    type IntoIter = std::vec::IntoIter<&'a mut Directive<D>>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        let predicates = self.predicates;
+        self.ledger
+            .directives_mut()
+            .map(|(_, d)| d)
+            .filter(|d| predicates.iter().all(|p| p(d)))
+            .collect::<Vec<_>>()
+            .into_iter()
+    }
+}
+
+impl<D: Decimal> Ledger<D> {
+    /// Returns a query over all directives of the ledger.
+    pub fn query(&self) -> Query<'_, D> {
+        Query {
+            ledger: self,
+            predicates: Vec::new(),
+        }
+    }
+
+    /// Returns a query over all directives of the ledger, for modification.
+    pub fn query_mut(&mut self) -> QueryMut<'_, D> {
+        QueryMut {
+            ledger: self,
+            predicates: Vec::new(),
+        }
+    }
+
+    /// Returns a query over the transactions of the ledger.
+    pub fn transactions(&self) -> Query<'_, D> {
+        self.query().filter(|d| transaction(d).is_some())
+    }
+
+    /// Returns a query over the transactions of the ledger, for modification.
+    pub fn transactions_mut(&mut self) -> QueryMut<'_, D> {
+        self.query_mut().filter(|d| transaction(d).is_some())
+    }
+}
+
+fn transaction<D>(d: &Directive<D>) -> Option<&Transaction<D>> {
+    d.content.transaction_opt()
+}
+
+/// Matches the account against a pattern, where `*` matches any characters.
+pub(crate) fn matches(pattern: &str, account: &str) -> bool {
+    let mut parts = pattern.split('*');
+    // The first part has to be a prefix, and the last one a suffix (split returns at least one).
+    let first = parts.next().unwrap_or_default();
+    let mut rest = match account.strip_prefix(first) {
+        Some(rest) => rest,
+        None => return false,
+    };
+    let parts: Vec<&str> = parts.collect();
+    let (last, middle) = match parts.split_last() {
+        Some(split) => split,
+        None => return rest.is_empty(),
+    };
+    for part in middle {
+        match rest.find(part) {
+            Some(pos) => rest = &rest[pos + part.len()..],
+            None => return false,
+        }
+    }
+    rest.ends_with(last)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    fn ledger() -> Ledger<rust_decimal::Decimal> {
+        let file = crate::parse(
+            r#"2023-01-01 open Assets:CH:Cash
+2023-01-02 * "Migros" "Food" #trip
+  Assets:CH:Cash -10 CHF
+  Expenses:Food
+2023-02-02 * "Coop" "Food" ^receipt
+  id: "1"
+  Assets:CH:Cash -20 CHF
+  Expenses:Food
+2023-03-02 * "Shop" "Clothes" #trip
+  Assets:US:Cash -30 USD
+  Expenses:Clothes
+"#,
+        )
+        .unwrap();
+        Ledger {
+            files: vec![("main.beancount".into(), file)],
+        }
+    }
+
+    fn narrations<'a>(
+        directives: impl IntoIterator<Item = &'a Directive<rust_decimal::Decimal>>,
+    ) -> Vec<&'a str> {
+        directives
+            .into_iter()
+            .filter_map(|d| transaction(d)?.narration.as_deref())
+            .collect()
+    }
+
+    #[test]
+    fn matches_account_patterns() {
+        assert!(matches("Assets:CH:*", "Assets:CH:Cash"));
+        assert!(!matches("Assets:CH:*", "Assets:US:Cash"));
+        assert!(matches("*:Cash", "Assets:US:Cash"));
+        assert!(matches("Assets:*:Cash", "Assets:US:Cash"));
+        assert!(matches("Assets:CH:Cash", "Assets:CH:Cash"));
+        assert!(!matches("Assets:CH", "Assets:CH:Cash"));
+    }
+
+    #[test]
+    fn filters_transactions() {
+        let l = ledger();
+        let from = chrono::NaiveDate::from_ymd_opt(2023, 1, 1).unwrap();
+        let to = chrono::NaiveDate::from_ymd_opt(2023, 3, 1).unwrap();
+        assert_eq!(
+            narrations(l.transactions().with_tag("trip")),
+            ["Food", "Clothes"]
+        );
+        assert_eq!(
+            narrations(l.transactions().between(from, to).touching("Assets:CH:*")),
+            ["Food", "Food"]
+        );
+        assert_eq!(l.query().touching("Assets:CH:*").into_iter().count(), 3);
+        assert_eq!(narrations(l.transactions().with_link("receipt")), ["Food"]);
+        assert_eq!(narrations(l.transactions().with_payee("Shop")), ["Clothes"]);
+        assert_eq!(
+            narrations(
+                l.transactions()
+                    .with_metadata("id", MetadataValue::String("1".into()))
+            ),
+            ["Food"]
+        );
+    }
+
+    #[test]
+    fn modifies_matching_transactions() {
+        let mut l = ledger();
+        for d in l.transactions_mut().with_tag("trip") {
+            d.metadata
+                .insert("trip".into(), MetadataValue::String("yes".into()));
+        }
+        assert_eq!(
+            l.transactions()
+                .with_metadata_key("trip")
+                .into_iter()
+                .count(),
+            2
+        );
+    }
+}
@@ -0,0 +1,217 @@
+//! Choosing the file of a multi-file ledger that new directives are added to.
+//!
+//! ```
+//! # use beanru::{routing::{Route, Routing}, types::*};
+//! # fn run(ledger: &mut Ledger<rust_decimal::Decimal>, imported: BeancountFile<rust_decimal::Decimal>) -> beanru::Result<()> {
+//! let routing = Routing {
+//!     routes: vec![
+//!         Route::new("revolut/{year}.beancount").account_prefix("Assets:CH:Revolut"),
+//!         Route::new("{year}.beancount"),
+//!     ],
+//! };
+//! for d in imported.directives {
+//!     ledger.insert(d, &routing)?;
+//! }
+//! # Ok(())
+//! # }
+//! ```
+use crate::{check::is_same_or_child, types::*, Error};
+use std::path::{Path, PathBuf};
+
+/// Rules that choose the file a directive is inserted into, see Ledger::insert.
+///
+/// The routes are checked in order and the first matching one is used. If none matches, the
+/// directive goes to the main file of the ledger (the first one).
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Routing {
+    pub routes: Vec<Route>,
+}
+
+/// A single routing rule.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Route {
+    /// The path of the file, relative to the directory of the main file. `{year}` is replaced
+    /// with the year of the directive, e.g. `{year}.beancount` routes every year to its own
+    /// file.
+    pub path: String,
+    /// If set, only the directives that use the account or one of its sub-accounts match the
+    /// route. For transactions, it is enough that one posting matches.
+    pub account_prefix: Option<Account>,
+}
+
+impl Route {
+    /// Returns the route that matches all directives.
+    pub fn new(path: impl Into<String>) -> Self {
+        Self {
+            path: path.into(),
+            account_prefix: None,
+        }
+    }
+
+    /// Limits the route to the directives using given account or its sub-accounts.
+    pub fn account_prefix(mut self, prefix: impl Into<Account>) -> Self {
+        self.account_prefix = Some(prefix.into());
+        self
+    }
+
+    fn matches<D>(&self, d: &Directive<D>) -> bool {
+        let prefix = match &self.account_prefix {
+            Some(prefix) => prefix,
+            None => return true,
+        };
+        d.accounts().iter().any(|a| is_same_or_child(a, prefix))
+    }
+
+    fn path<D>(&self, d: &Directive<D>) -> PathBuf {
+        PathBuf::from(
+            self.path
+                .replace("{year}", &d.date.format("%Y").to_string()),
+        )
+    }
+}
+
+impl<D: Decimal> Ledger<D> {
+    /// Inserts the directive into the file chosen by given routing rules.
+    ///
+    /// Within the file, the directive is placed after all directives that come before or on the
+    /// same day in the canonical order. If the routed file is not part of the ledger yet, it is
+    /// created and included from the main file.
+    ///
+    /// Returns the path of the file the directive was inserted into.
+    pub fn insert(
+        &mut self,
+        directive: Directive<D>,
+        routing: &Routing,
+    ) -> crate::Result<&Path, D> {
+        let main = match self.files.first() {
+            Some((path, _)) => path.clone(),
+            None => return Err(Error::EmptyLedger),
+        };
+        let route = routing.routes.iter().find(|r| r.matches(&directive));
+        let index = match route {
+            None => 0,
+            Some(route) => {
+                let relative = route.path(&directive);
+                let path = main.parent().unwrap_or(Path::new("")).join(&relative);
+                match self.files.iter().position(|(p, _)| *p == path) {
+                    Some(index) => index,
+                    None => {
+                        self.files[0].1.includes.push(relative);
+                        self.files.push((path, BeancountFile::default()));
+                        self.files.len() - 1
+                    }
+                }
+            }
+        };
+        let (path, file) = &mut self.files[index];
+        let key = directive.sort_key();
+        let pos = file
+            .directives
+            .iter()
+            .rposition(|d| d.sort_key() <= key)
+            .map_or(0, |i| i + 1);
+        file.directives.insert(pos, directive);
+        Ok(path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    fn directive(input: &str) -> Directive<rust_decimal::Decimal> {
+        crate::parse(input)
+            .unwrap()
+            .directives
+            .into_iter()
+            .next()
+            .unwrap()
+    }
+
+    #[test]
+    fn inserts_into_main_file_in_date_order() {
+        let mut ledger = Ledger {
+            files: vec![(
+                "ledger/main.beancount".into(),
+                crate::parse("2020-01-01 open Assets:Cash\n2020-03-01 close Assets:Cash\n")
+                    .unwrap(),
+            )],
+        };
+        let path = ledger
+            .insert(
+                directive("2020-02-01 balance Assets:Cash 0 CHF\n"),
+                &Routing::default(),
+            )
+            .unwrap();
+        assert_eq!(path, Path::new("ledger/main.beancount"));
+        assert_eq!(
+            ledger.files[0].1.to_string(),
+            "2020-01-01 open Assets:Cash\n2020-02-01 balance Assets:Cash 0 CHF\n2020-03-01 close Assets:Cash\n"
+        );
+    }
+
+    #[test]
+    fn routes_by_account_prefix_and_year() {
+        let mut ledger = Ledger {
+            files: vec![("ledger/main.beancount".into(), BeancountFile::default())],
+        };
+        let routing = Routing {
+            routes: vec![
+                Route::new("bank/{year}.beancount").account_prefix("Assets:Bank"),
+                Route::new("{year}.beancount"),
+            ],
+        };
+        let path = ledger
+            .insert(directive("2020-02-01 open Assets:Bank:CHF\n"), &routing)
+            .unwrap();
+        assert_eq!(path, Path::new("ledger/bank/2020.beancount"));
+        let path = ledger
+            .insert(directive("2021-02-01 open Assets:Cash\n"), &routing)
+            .unwrap();
+        assert_eq!(path, Path::new("ledger/2021.beancount"));
+        ledger
+            .insert(directive("2020-03-01 close Assets:Bank:CHF\n"), &routing)
+            .unwrap();
+        assert_eq!(ledger.files.len(), 3);
+        assert_eq!(ledger.files[1].1.directives.len(), 2);
+        assert_eq!(
+            ledger.files[0].1.includes,
+            vec![
+                PathBuf::from("bank/2020.beancount"),
+                PathBuf::from("2021.beancount")
+            ]
+        );
+    }
+
+    #[test]
+    fn routes_by_account_segments() {
+        let mut ledger = Ledger {
+            files: vec![("main.beancount".into(), BeancountFile::default())],
+        };
+        let routing = Routing {
+            routes: vec![Route::new("bank.beancount").account_prefix("Assets:Bank")],
+        };
+        let path = ledger
+            .insert(
+                directive("2020-02-01 open Assets:BankOfAmerica\n"),
+                &routing,
+            )
+            .unwrap();
+        assert_eq!(path, Path::new("main.beancount"));
+        let path = ledger
+            .insert(directive("2020-02-01 open Assets:Bank\n"), &routing)
+            .unwrap();
+        assert_eq!(path, Path::new("bank.beancount"));
+    }
+
+    #[test]
+    fn fails_on_empty_ledger() {
+        let mut ledger: Ledger<rust_decimal::Decimal> = Ledger::default();
+        let res = ledger.insert(
+            directive("2020-02-01 open Assets:Cash\n"),
+            &Routing::default(),
+        );
+        assert!(matches!(res, Err(Error::EmptyLedger)));
+    }
+}
@@ -0,0 +1,229 @@
+//! Serde support for the types model, enabled with the `serialize` feature.
+//!
+//! The JSON shape is meant to be consumed by other tools, so it is kept stable:
+//!
+//! ```json
+//! {
+//!   "files": [
+//!     {
+//!       "path": "main.beancount",
+//!       "options": [["operating_currency", "CHF"]],
+//!       "includes": ["2023.beancount"],
+//!       "directives": [
+//!         {
+//!           "date": "2023-01-01",
+//!           "type": "transaction",
+//!           "flag": "*",
+//!           "payee": null,
+//!           "narration": "Coffee",
+//!           "tags": [],
+//!           "links": [],
+//!           "postings": [
+//!             {
+//!               "flag": null,
+//!               "account": "Expenses:Food",
+//!               "amount": { "value": "3.50", "currency": "CHF" },
+//!               "cost": null,
+//!               "price": null,
+//!               "metadata": {},
+//!               "autocomputed": false
+//!             }
+//!           ],
+//!           "balanced": true,
+//!           "metadata": { "id": { "string": "abc" } },
+//!           "line": 3,
+//!           "pragmas": [{ "tag": "trip" }]
+//!         }
+//!       ]
+//!     }
+//!   ]
+//! }
+//! ```
+//!
+//! - All numbers are written as strings, exactly as displayed (e.g. `"3.50"`), so that no
+//!   precision is lost.
+//! - Dates are written as `YYYY-MM-DD`, accounts and currencies as plain strings.
+//! - The `type` of a directive is one of `balance`, `close`, `commodity`, `event`, `open`,
+//!   `pad`, `price` or `transaction`, and the fields of the directive are written next to it.
+//!   The commodity directive has a single `currency` field.
+//! - Metadata values are objects with a single key: `string`, `number` or `currency`.
+//! - `pragmas` lists the `pushtag` and `pushmeta` in effect for the directive, as `{"tag": tag}`
+//!   and `{"meta": [key, value]}`. Their tags and metadata are already applied to the directive.
+//! - Posting prices are `{"unit": amount}` for `@` and `{"total": amount}` for `@@`.
+use crate::types::*;
+use serde::{de::Error as _, Deserialize, Deserializer, Serialize, Serializer};
+use std::{collections::HashSet, path::PathBuf};
+
+/// Serializes a decimal as a string.
+pub(crate) mod decimal {
+    use super::*;
+
+    pub fn serialize<D: Decimal, S: Serializer>(value: &D, s: S) -> Result<S::Ok, S::Error> {
+        s.collect_str(value)
+    }
+
+    pub fn deserialize<'de, D: Decimal, De: Deserializer<'de>>(d: De) -> Result<D, De::Error> {
+        let s = String::deserialize(d)?;
+        s.parse()
+            .map_err(|_| De::Error::custom(format!("invalid number: {}", s)))
+    }
+}
+
+/// Serializes an optional decimal as a string or null.
+pub(crate) mod option_decimal {
+    use super::*;
+
+    pub fn serialize<D: Decimal, S: Serializer>(
+        value: &Option<D>,
+        s: S,
+    ) -> Result<S::Ok, S::Error> {
+        match value {
+            Some(value) => s.collect_str(value),
+            None => s.serialize_none(),
+        }
+    }
+
+    pub fn deserialize<'de, D: Decimal, De: Deserializer<'de>>(
+        d: De,
+    ) -> Result<Option<D>, De::Error> {
+        match Option::<String>::deserialize(d)? {
+            Some(s) => s
+                .parse()
+                .map(Some)
+                .map_err(|_| De::Error::custom(format!("invalid number: {}", s))),
+            None => Ok(None),
+        }
+    }
+}
+
+/// Serializes the commodity directive as `{"currency": ...}`, as the internally tagged
+/// DirectiveContent can not hold a plain string.
+pub(crate) mod commodity {
+    use super::*;
+
+    #[derive(Serialize, Deserialize)]
+    struct Commodity<C> {
+        currency: C,
+    }
+
+    pub fn serialize<S: Serializer>(currency: &Currency, s: S) -> Result<S::Ok, S::Error> {
+        Commodity { currency }.serialize(s)
+    }
+
+    pub fn deserialize<'de, De: Deserializer<'de>>(d: De) -> Result<Currency, De::Error> {
+        Ok(Commodity::deserialize(d)?.currency)
+    }
+}
+
+/// Serializes the currencies of an open directive sorted, so the output does not depend on the
+/// order of the set.
+pub(crate) fn sorted_currencies<S: Serializer>(
+    currencies: &HashSet<Currency>,
+    s: S,
+) -> Result<S::Ok, S::Error> {
+    let mut sorted: Vec<&Currency> = currencies.iter().collect();
+    sorted.sort();
+    s.collect_seq(sorted)
+}
+
+/// Serializes the files of the ledger as objects with a `path` field.
+pub(crate) mod ledger_files {
+    use super::*;
+
+    #[derive(Serialize)]
+    #[serde(bound = "D: Decimal")]
+    struct FileRef<'a, D> {
+        path: &'a PathBuf,
+        #[serde(flatten)]
+        file: &'a BeancountFile<D>,
+    }
+
+    #[derive(Deserialize)]
+    #[serde(bound = "D: Decimal")]
+    struct File<D> {
+        path: PathBuf,
+        #[serde(flatten)]
+        file: BeancountFile<D>,
+    }
+
+    pub fn serialize<D: Decimal, S: Serializer>(
+        files: &[(PathBuf, BeancountFile<D>)],
+        s: S,
+    ) -> Result<S::Ok, S::Error> {
+        s.collect_seq(files.iter().map(|(path, file)| FileRef { path, file }))
+    }
+
+    pub fn deserialize<'de, D: Decimal, De: Deserializer<'de>>(
+        d: De,
+    ) -> Result<Vec<(PathBuf, BeancountFile<D>)>, De::Error> {
+        Ok(Vec::<File<D>>::deserialize(d)?
+            .into_iter()
+            .map(|f| (f.path, f.file))
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    fn ledger(input: &str) -> Ledger<rust_decimal::Decimal> {
+        Ledger {
+            files: vec![("main.beancount".into(), crate::parse(input).unwrap())],
+        }
+    }
+
+    #[test]
+    fn writes_documented_shape() {
+        let l = ledger(
+            r#"2023-01-01 commodity CHF
+2023-01-02 * "Coffee"
+  Expenses:Food 3.50 CHF @ 1.10 USD
+  Assets:Cash
+"#,
+        );
+        let json = serde_json::to_value(&l).unwrap();
+        let file = &json["files"][0];
+        assert_eq!(file["path"], "main.beancount");
+        assert_eq!(file["directives"][0]["type"], "commodity");
+        assert_eq!(file["directives"][0]["currency"], "CHF");
+        let t = &file["directives"][1];
+        assert_eq!(t["type"], "transaction");
+        assert_eq!(t["date"], "2023-01-02");
+        assert_eq!(t["line"], 2);
+        assert_eq!(
+            t["postings"][0]["amount"],
+            serde_json::json!({"value": "3.50", "currency": "CHF"})
+        );
+        assert_eq!(t["postings"][0]["price"]["unit"]["value"], "1.10");
+        assert_eq!(t["postings"][1]["autocomputed"], true);
+    }
+
+    #[test]
+    fn writes_open_currencies_sorted() {
+        let input = "2023-01-01 open Assets:Cash CHF,USD,EUR,GBP,JPY\n";
+        let json = serde_json::to_string(&ledger(input)).unwrap();
+        assert_eq!(serde_json::to_string(&ledger(input)).unwrap(), json);
+        let value: serde_json::Value = serde_json::from_str(&json).unwrap();
+        assert_eq!(
+            value["files"][0]["directives"][0]["currencies"],
+            serde_json::json!(["CHF", "EUR", "GBP", "JPY", "USD"])
+        );
+    }
+
+    #[test]
+    fn round_trips_through_json() {
+        let l = ledger(
+            r#"2023-01-01 open Assets:Stock HOOL "FIFO"
+2023-01-02 * "Buy" #tag ^link
+  id: "abc"
+  Assets:Stock 10 HOOL {100.00 USD, 2023-01-01}
+  Assets:Cash -1000.00 USD
+"#,
+        );
+        let json = serde_json::to_string(&l).unwrap();
+        let back: Ledger<rust_decimal::Decimal> = serde_json::from_str(&json).unwrap();
+        assert_eq!(back, l);
+    }
+}
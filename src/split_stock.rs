@@ -4,7 +4,7 @@ pub fn split_stock(
     ledger: &mut Ledger<rust_decimal::Decimal>,
     commodity: &Currency,
     ratio: rust_decimal::Decimal,
-) -> anyhow::Result<()> {
+) -> crate::Result<()> {
     for (_, file) in &mut ledger.files {
         for directive in &mut file.directives {
             match &mut directive.content {
@@ -41,7 +41,7 @@ pub fn split_stock_posting(
     posting: &mut Posting<rust_decimal::Decimal>,
     commodity: &Currency,
     ratio: rust_decimal::Decimal,
-) -> anyhow::Result<()> {
+) -> crate::Result<()> {
     let amount = match &mut posting.amount {
         Some(amount) => amount,
         None => return Ok(()),
@@ -51,8 +51,9 @@ pub fn split_stock_posting(
     }
     amount.value *= ratio;
     if let Some(cost) = &mut posting.cost {
-        if let Some(cost_amount) = &mut cost.amount {
-            cost_amount.value /= ratio;
+        // The total cost (`{{...}}`) does not change, only the per unit one.
+        if let Some(number_per) = &mut cost.number_per {
+            *number_per /= ratio;
         }
     }
     if let Some(PostingPrice::Unit(price)) = &mut posting.price {
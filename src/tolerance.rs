@@ -0,0 +1,221 @@
+//! Tolerances used when verifying that amounts balance.
+//!
+//! Beancount does not require amounts to balance exactly, instead the tolerance is inferred from
+//! the number of decimal places the amounts are written with. See
+//! <https://beancount.github.io/docs/precision_tolerances.html> for details.
+use crate::types::*;
+use crate::{Error, Result};
+use std::collections::HashMap;
+
+/// Tolerance rules of a ledger.
+///
+/// The rules can be configured with the following options:
+/// - `inferred_tolerance_default`, e.g. `CHF:0.01` or `*:0.001`, used for currencies that are
+///   only written as integers,
+/// - `inferred_tolerance_multiplier` (or `tolerance_multiplier`), the multiplier applied to the
+///   last digit of a number (0.5 by default).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Tolerance<D> {
+    defaults: HashMap<Currency, D>,
+    default: D,
+    multiplier: D,
+}
+
+impl<D> Default for Tolerance<D>
+where
+    D: Decimal,
+{
+    fn default() -> Self {
+        Self {
+            defaults: HashMap::new(),
+            default: 0.into(),
+            multiplier: D::from(5) / D::from(10),
+        }
+    }
+}
+
+impl<D> Tolerance<D>
+where
+    D: Decimal,
+{
+    /// Returns the tolerance rules configured by the options of the ledger.
+    pub fn from_ledger(ledger: &Ledger<D>) -> Result<Self, D> {
+        Self::from_options(ledger.files.iter().flat_map(|(_, f)| &f.options))
+    }
+
+    /// Returns the tolerance rules configured by given options.
+    ///
+    /// Options not related to tolerances are ignored.
+    pub fn from_options<'a>(
+        options: impl IntoIterator<Item = &'a (String, String)>,
+    ) -> Result<Self, D> {
+        let mut res = Self::default();
+        for (name, value) in options {
+            let invalid = || Error::InvalidOption {
+                name: name.clone(),
+                value: value.clone(),
+            };
+            match name.as_str() {
+                "inferred_tolerance_default" => {
+                    let (currency, number) = value.split_once(':').ok_or_else(invalid)?;
+                    let number = parse_number(number).ok_or_else(invalid)?;
+                    if currency == "*" {
+                        res.default = number;
+                    } else {
+                        res.defaults.insert(currency.into(), number);
+                    }
+                }
+                "inferred_tolerance_multiplier" | "tolerance_multiplier" => {
+                    res.multiplier = parse_number(value).ok_or_else(invalid)?;
+                }
+                _ => (),
+            }
+        }
+        Ok(res)
+    }
+
+    /// Returns the tolerance of a currency when it can not be inferred from the amounts.
+    pub fn default_for(&self, currency: &Currency) -> D {
+        self.defaults
+            .get(currency)
+            .cloned()
+            .unwrap_or_else(|| self.default.clone())
+    }
+
+    /// Returns the tolerance implied by the precision of given number, or None if the number is
+    /// an integer.
+    pub fn of_number(&self, value: &D) -> Option<D> {
+        let places = decimal_places(value);
+        if places == 0 {
+            return None;
+        }
+        Some(last_digit::<D>(places) * self.multiplier.clone())
+    }
+
+    /// Infers the tolerance of every currency used by the units of given postings.
+    ///
+    /// When the same currency is written with different precisions, the largest tolerance is
+    /// used. Currencies only written as integers get their default tolerance.
+    pub fn infer(&self, postings: &[Posting<D>]) -> HashMap<Currency, D> {
+        let mut tolerances: HashMap<Currency, D> = HashMap::new();
+        for posting in postings {
+            if posting.autocomputed {
+                continue;
+            }
+            let units = match &posting.amount {
+                Some(units) => units,
+                None => continue,
+            };
+            let tolerance = self
+                .of_number(&units.value)
+                .unwrap_or_else(|| self.default_for(&units.currency));
+            let entry = tolerances
+                .entry(units.currency.clone())
+                .or_insert_with(|| tolerance.clone());
+            if tolerance > *entry {
+                *entry = tolerance;
+            }
+        }
+        tolerances
+    }
+
+    /// Returns the tolerance of a balance assertion.
+    ///
+    /// As in beancount, it is twice the tolerance implied by the precision of the amount, so a
+    /// balance of `10.00 CHF` accepts anything from 9.99 to 10.01. Integer amounts have to
+    /// match exactly.
+    pub fn for_balance(&self, amount: &Amount<D>) -> D {
+        match self.of_number(&amount.value) {
+            Some(t) => t * 2.into(),
+            None => 0.into(),
+        }
+    }
+}
+
+/// Returns true if the absolute value of given number does not exceed the tolerance.
+pub fn is_within<D: Decimal>(value: &D, tolerance: &D) -> bool {
+    let abs = if *value < 0.into() {
+        -value.clone()
+    } else {
+        value.clone()
+    };
+    abs <= *tolerance
+}
+
+/// Returns the number of digits after the decimal point, as written by the Display impl.
+pub(crate) fn decimal_places<D: Decimal>(value: &D) -> u32 {
+    let s = value.to_string();
+    match s.find('.') {
+        Some(pos) => (s.len() - pos - 1) as u32,
+        None => 0,
+    }
+}
+
+/// Returns the value of the last digit of a number with given decimal places, e.g. 0.01 for 2.
+pub(crate) fn last_digit<D: Decimal>(places: u32) -> D {
+    let mut value: D = 1.into();
+    for _ in 0..places {
+        value = value / 10.into();
+    }
+    value
+}
+
+fn parse_number<D: Decimal>(s: &str) -> Option<D> {
+    s.trim().parse::<D>().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+    use rust_decimal::Decimal;
+
+    fn d(s: &str) -> Decimal {
+        s.parse().unwrap()
+    }
+
+    fn options(options: &[(&str, &str)]) -> Vec<(String, String)> {
+        options
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn infers_tolerance_from_decimal_places() {
+        let t: Tolerance<Decimal> = Tolerance::default();
+        assert_eq!(t.of_number(&d("1.00")), Some(d("0.005")));
+        assert_eq!(t.of_number(&d("1.5")), Some(d("0.05")));
+        assert_eq!(t.of_number(&d("1")), None);
+    }
+
+    #[test]
+    fn uses_options() {
+        let t: Tolerance<Decimal> = Tolerance::from_options(&options(&[
+            ("inferred_tolerance_default", "CHF:0.01"),
+            ("inferred_tolerance_default", "*:0.001"),
+            ("inferred_tolerance_multiplier", "1.2"),
+        ]))
+        .unwrap();
+        assert_eq!(t.default_for(&"CHF".into()), d("0.01"));
+        assert_eq!(t.default_for(&"USD".into()), d("0.001"));
+        assert_eq!(t.of_number(&d("1.00")), Some(d("0.012")));
+    }
+
+    #[test]
+    fn rejects_invalid_options() {
+        let res: Result<Tolerance<Decimal>, Decimal> =
+            Tolerance::from_options(&options(&[("inferred_tolerance_default", "CHF")]));
+        assert!(matches!(res, Err(Error::InvalidOption { .. })));
+    }
+
+    #[test]
+    fn balance_tolerance_is_twice_the_inferred_one() {
+        let t: Tolerance<Decimal> = Tolerance::default();
+        let amount = Amount {
+            value: d("10.00"),
+            currency: "CHF".into(),
+        };
+        assert_eq!(t.for_balance(&amount), d("0.010"));
+    }
+}
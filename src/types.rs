@@ -1,18 +1,24 @@
-use crate::bag::Bag;
-use beancount_parser as parser;
+use crate::format::{Format, FormatOptions};
+use crate::tolerance::Tolerance;
+use crate::{Error, Location};
+use indexmap::{IndexMap, IndexSet};
 use std::{
-    collections::{HashMap, HashSet},
+    collections::HashSet,
     fmt::{Debug, Display},
     future::Future,
     hash::Hash,
     ops::{Add, AddAssign, Div, Mul, Neg, Sub},
     path::{Path, PathBuf},
     str::FromStr,
+    sync::Arc,
 };
 
 /// A whole Ledger, containing multiple beancount files.
 #[derive(Debug, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "serialize", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serialize", serde(bound = "D: Decimal"))]
 pub struct Ledger<D> {
+    #[cfg_attr(feature = "serialize", serde(with = "crate::serialize::ledger_files"))]
     pub files: Vec<(PathBuf, BeancountFile<D>)>,
 }
 
@@ -24,47 +30,104 @@ impl<D> Ledger<D> {
     pub async fn read<F, R>(
         start_path: impl AsRef<Path>,
         read_to_string: F,
-    ) -> anyhow::Result<Ledger<D>>
+    ) -> crate::Result<Ledger<D>, D>
     where
         D: Decimal,
         F: Fn(PathBuf) -> R,
-        R: Future<Output = anyhow::Result<String>>,
+        R: Future<Output = std::io::Result<String>>,
     {
         let mut queue: Vec<PathBuf> = vec![start_path.as_ref().into()];
         let mut files: Vec<(PathBuf, BeancountFile<D>)> = Vec::new();
+        let mut interner = Interner::default();
         // TODO: parallelize it (as we could be reading all files at once).
         while let Some(p) = queue.pop() {
-            let b = parser::parse::<D>(&read_to_string(p.clone()).await?)?;
+            let content = read_to_string(p.clone())
+                .await
+                .map_err(|source| Error::Read {
+                    path: p.clone(),
+                    source,
+                })?;
+            let b = crate::parse::parse_file::<D>(Some(&p), &content, &mut interner)?;
             for incl in &b.includes {
                 let mut x = p.clone();
                 x.pop();
                 x.push(incl);
                 queue.push(x);
             }
-            files.push((p, b.into()));
+            files.push((p, b));
         }
         Ok(Ledger { files })
     }
 
+    /// Returns the directives of all files, in the canonical order of beancount: by date, then
+    /// by type (open and balance first, close last), then by position in the ledger.
+    ///
+    /// Together with every directive, the path of the file that contains it is returned.
+    pub fn directives(&self) -> impl Iterator<Item = (&PathBuf, &Directive<D>)> {
+        let mut directives: Vec<(&PathBuf, &Directive<D>)> = self
+            .files
+            .iter()
+            .flat_map(|(path, file)| file.directives.iter().map(move |d| (path, d)))
+            .collect();
+        // The sort is stable, so the directives of the same day and type stay in file order.
+        directives.sort_by_key(|(_, d)| d.sort_key());
+        directives.into_iter()
+    }
+
+    /// Same as directives, but allows modifying them.
+    pub fn directives_mut(&mut self) -> impl Iterator<Item = (&PathBuf, &mut Directive<D>)> {
+        let mut directives: Vec<(&PathBuf, &mut Directive<D>)> = self
+            .files
+            .iter_mut()
+            .flat_map(|(path, file)| {
+                let path: &PathBuf = path;
+                file.directives.iter_mut().map(move |d| (path, d))
+            })
+            .collect();
+        directives.sort_by_key(|(_, d)| d.sort_key());
+        directives.into_iter()
+    }
+
     /// Writes the beancount ledger, starting at given path and following all includes.
     ///
     /// It uses given read_to_string function to read the content at given path. To read from standard
     /// file system, tokio::fs::read_to_string can be used.
-    pub async fn write<F, R>(&self, write: F) -> anyhow::Result<()>
+    pub async fn write<F, R>(&self, write: F) -> crate::Result<(), D>
+    where
+        D: Decimal,
+        F: Fn(PathBuf, Vec<u8>) -> R,
+        R: Future<Output = std::io::Result<()>>,
+    {
+        self.write_with_options(&Default::default(), write).await
+    }
+
+    /// Same as write, but uses given options to format the files.
+    pub async fn write_with_options<F, R>(
+        &self,
+        options: &FormatOptions,
+        write: F,
+    ) -> crate::Result<(), D>
     where
         D: Decimal,
         F: Fn(PathBuf, Vec<u8>) -> R,
-        R: Future<Output = anyhow::Result<()>>,
+        R: Future<Output = std::io::Result<()>>,
     {
         // TODO: parallelize it
         for (path, file) in &self.files {
-            write(path.clone(), file.to_string().into_bytes()).await?;
+            write(path.clone(), file.display(options).to_string().into_bytes())
+                .await
+                .map_err(|source| Error::Write {
+                    path: path.clone(),
+                    source,
+                })?;
         }
         Ok(())
     }
 }
 
 #[derive(Debug, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "serialize", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serialize", serde(bound = "D: Decimal"))]
 pub struct BeancountFile<D> {
     pub options: Vec<(String, String)>,
     pub includes: Vec<PathBuf>,
@@ -72,86 +135,391 @@ pub struct BeancountFile<D> {
 }
 
 #[derive(Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serialize", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serialize", serde(bound = "D: Decimal"))]
 pub struct Directive<D> {
     pub date: chrono::NaiveDate,
+    #[cfg_attr(feature = "serialize", serde(flatten))]
     pub content: DirectiveContent<D>,
-    pub metadata: HashMap<String, MetadataValue<D>>,
+    pub metadata: IndexMap<String, MetadataValue<D>>,
+    /// Line of the directive in the source file, None for directives that were not parsed.
+    pub line: Option<u32>,
+    /// The `pushtag` and `pushmeta` pragmas in effect for the directive, in the order they were
+    /// pushed. Their tags (for transactions) and metadata are already applied to the directive,
+    /// this is only used to write the pragmas back, see FormatOptions::stack_pragmas.
+    pub pragmas: Vec<Pragma<D>>,
 }
 
-// TODO: rename to Commodity
-#[derive(Debug, Clone, PartialOrd, Ord, PartialEq, Eq, Hash)]
-pub struct Currency(pub String);
+/// A stack pragma, i.e. `pushtag #tag` or `pushmeta key: value`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serialize", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(
+    feature = "serialize",
+    serde(bound = "D: Decimal", rename_all = "lowercase")
+)]
+pub enum Pragma<D> {
+    Tag(String),
+    Meta(String, MetadataValue<D>),
+}
+
+impl<D> Directive<D> {
+    /// Returns the key of the canonical order of directives, without the position in the file.
+    pub(crate) fn sort_key(&self) -> (chrono::NaiveDate, i32) {
+        let order = match self.content {
+            DirectiveContent::Open(_) => -2,
+            DirectiveContent::Balance(_) => -1,
+            DirectiveContent::Close(_) => 2,
+            _ => 0,
+        };
+        (self.date, order)
+    }
+
+    /// Returns a directive without metadata, that was not parsed from a file.
+    pub fn new(date: chrono::NaiveDate, content: DirectiveContent<D>) -> Self {
+        Self {
+            date,
+            content,
+            metadata: IndexMap::new(),
+            line: None,
+            pragmas: Vec::new(),
+        }
+    }
+
+    /// Returns the location of the directive, given the path of the file that contains it.
+    pub fn location(&self, path: &Path) -> Location {
+        Location {
+            path: path.into(),
+            line: self.line,
+        }
+    }
+
+    /// Returns all accounts used by the directive.
+    pub fn accounts(&self) -> Vec<&Account> {
+        match &self.content {
+            DirectiveContent::Balance(b) => vec![&b.account],
+            DirectiveContent::Close(c) => vec![&c.account],
+            DirectiveContent::Open(o) => vec![&o.account],
+            DirectiveContent::Pad(p) => vec![&p.account, &p.source_account],
+            DirectiveContent::Transaction(t) => t.postings.iter().map(|p| &p.account).collect(),
+            DirectiveContent::Commodity(_)
+            | DirectiveContent::Event(_)
+            | DirectiveContent::Price(_) => Vec::new(),
+        }
+    }
+}
+
+/// A shared string, cheap to clone.
+///
+/// Clones share the same allocation, and so do the symbols of a parsed ledger with the same
+/// name (see Interner), so comparing the pointers first avoids comparing the strings in the
+/// common case.
+#[derive(Clone)]
+struct Symbol(Arc<str>);
+
+/// Interner of the accounts and currencies of a ledger, so that the equal names share one
+/// allocation. It is owned by the parsing of a ledger, see Ledger::read.
+#[derive(Default)]
+pub(crate) struct Interner {
+    symbols: HashSet<Arc<str>>,
+}
+
+impl Interner {
+    fn symbol(&mut self, symbol: &mut Symbol) {
+        match self.symbols.get(&symbol.0) {
+            Some(s) => symbol.0 = s.clone(),
+            None => {
+                self.symbols.insert(symbol.0.clone());
+            }
+        }
+    }
 
-impl From<&str> for Currency {
-    fn from(value: &str) -> Self {
-        Currency(value.to_string())
+    pub(crate) fn account(&mut self, account: &mut Account) {
+        self.symbol(&mut account.0)
     }
+
+    pub(crate) fn currency(&mut self, currency: &mut Currency) {
+        self.symbol(&mut currency.0)
+    }
+}
+
+impl Symbol {
+    fn new(value: &str) -> Self {
+        Symbol(value.into())
+    }
+}
+
+impl PartialEq for Symbol {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.0, &other.0) || self.0 == other.0
+    }
+}
+
+impl Eq for Symbol {}
+
+impl Hash for Symbol {
+    fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+        self.0.hash(state)
+    }
+}
+
+impl PartialOrd for Symbol {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Symbol {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        self.0.cmp(&other.0)
+    }
+}
+
+impl Debug for Symbol {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        Debug::fmt(&*self.0, f)
+    }
+}
+
+/// Implements the constructors and string access of a shared identifier.
+macro_rules! impl_symbol {
+    ($t:ident) => {
+        impl $t {
+            pub fn new(value: &str) -> Self {
+                $t(Symbol::new(value))
+            }
+
+            pub fn as_str(&self) -> &str {
+                &self.0 .0
+            }
+        }
+
+        impl From<&str> for $t {
+            fn from(value: &str) -> Self {
+                $t::new(value)
+            }
+        }
+
+        impl From<String> for $t {
+            fn from(value: String) -> Self {
+                $t::new(&value)
+            }
+        }
+
+        impl From<$t> for String {
+            fn from(value: $t) -> Self {
+                value.as_str().to_string()
+            }
+        }
+
+        impl AsRef<str> for $t {
+            fn as_ref(&self) -> &str {
+                self.as_str()
+            }
+        }
+    };
 }
 
+// TODO: rename to Commodity
+/// A currency (commodity), shared so that it is cheap to clone.
+#[derive(Debug, Clone, PartialOrd, Ord, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serialize", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serialize", serde(from = "String", into = "String"))]
+pub struct Currency(Symbol);
+
+impl_symbol!(Currency);
+
 #[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serialize", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(
+    feature = "serialize",
+    serde(bound = "D: Decimal", rename_all = "lowercase")
+)]
 pub enum MetadataValue<D> {
     String(String),
+    #[cfg_attr(feature = "serialize", serde(with = "crate::serialize::decimal"))]
     Number(D),
     Currency(Currency),
 }
 
 #[derive(Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serialize", derive(serde::Serialize, serde::Deserialize))]
 pub struct Pad {
     pub account: Account,
     pub source_account: Account,
 }
 
 #[derive(Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serialize", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serialize", serde(bound = "D: Decimal"))]
 pub struct Balance<D> {
     pub account: Account,
     pub amount: Amount<D>,
 }
 
 #[derive(Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serialize", derive(serde::Serialize, serde::Deserialize))]
 pub struct Event {
     pub name: String,
     pub value: String,
 }
 
 #[derive(Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serialize", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serialize", serde(bound = "D: Decimal"))]
 pub struct Transaction<D> {
     pub flag: Option<char>,
     pub payee: Option<String>,
     pub narration: Option<String>,
-    pub tags: HashSet<String>,
-    pub links: HashSet<String>,
+    /// Tags (without `#`), in the order they were written.
+    pub tags: IndexSet<String>,
+    /// Links (without `^`), in the order they were written.
+    pub links: IndexSet<String>,
     pub postings: Vec<Posting<D>>,
     pub balanced: bool,
 }
 
 #[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serialize", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serialize", serde(bound = "D: Decimal"))]
 pub struct Posting<D> {
     pub flag: Option<char>,
     pub account: Account,
     pub amount: Option<Amount<D>>,
     pub cost: Option<Cost<D>>,
     pub price: Option<PostingPrice<D>>,
-    pub metadata: HashMap<String, MetadataValue<D>>,
+    pub metadata: IndexMap<String, MetadataValue<D>>,
     // True if the amount is autocomputed.
     pub autocomputed: bool,
 }
 
-#[derive(Debug, Clone, PartialEq, Eq)]
+impl<D> Posting<D> {
+    /// Returns a builder of a posting to given account.
+    pub fn builder(account: impl Into<Account>) -> crate::builder::PostingBuilder<D> {
+        crate::builder::PostingBuilder::new(account.into())
+    }
+}
+
+/// Cost specification of a posting, i.e. everything between `{` and `}` (or `{{` and `}}`).
+///
+/// All parts are optional, as beancount allows partial specs (like `{}` or `{USD}`) that are
+/// filled in later during booking.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serialize", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serialize", serde(bound = "D: Decimal"))]
 pub struct Cost<D> {
-    pub amount: Option<Amount<D>>,
+    /// Cost per unit, e.g. `1.00` in `{1.00 USD}`.
+    #[cfg_attr(
+        feature = "serialize",
+        serde(with = "crate::serialize::option_decimal")
+    )]
+    pub number_per: Option<D>,
+    /// Total cost of all units, e.g. `10.00` in `{{10.00 USD}}` or `{1.00 # 10.00 USD}`.
+    #[cfg_attr(
+        feature = "serialize",
+        serde(with = "crate::serialize::option_decimal")
+    )]
+    pub number_total: Option<D>,
+    pub currency: Option<Currency>,
     pub date: Option<chrono::NaiveDate>,
+    pub label: Option<String>,
+    /// True if all lots should be merged into one at average cost (`{*}`).
+    pub merge: bool,
+}
+
+impl<D> Cost<D>
+where
+    D: Decimal,
+{
+    /// Returns the per unit cost spec `{amount}`.
+    pub fn per_unit(amount: Amount<D>) -> Self {
+        Self {
+            number_per: Some(amount.value),
+            currency: Some(amount.currency),
+            ..Default::default()
+        }
+    }
+
+    /// Returns the total cost spec `{{amount}}`.
+    pub fn total(amount: Amount<D>) -> Self {
+        Self {
+            number_total: Some(amount.value),
+            currency: Some(amount.currency),
+            ..Default::default()
+        }
+    }
+
+    /// Returns true if the cost number and currency are known.
+    pub fn is_complete(&self) -> bool {
+        self.currency.is_some() && (self.number_per.is_some() || self.number_total.is_some())
+    }
+
+    /// Returns the cost of a single unit, given the number of units held by the posting.
+    ///
+    /// Returns None if the spec is not complete.
+    pub fn unit_cost(&self, units: &D) -> Option<Amount<D>> {
+        let currency = self.currency.clone()?;
+        let value = match (&self.number_per, &self.number_total) {
+            (None, None) => return None,
+            (Some(per), None) => per.clone(),
+            (per, Some(total)) => {
+                if *units == 0.into() {
+                    return None;
+                }
+                let abs_units = if *units < 0.into() {
+                    -units.clone()
+                } else {
+                    units.clone()
+                };
+                per.clone().unwrap_or_default() + total.clone() / abs_units
+            }
+        };
+        Some(Amount { value, currency })
+    }
+
+    /// Returns the total cost of given number of units (with the sign of the units).
+    ///
+    /// Returns None if the spec is not complete.
+    pub fn total_cost(&self, units: &D) -> Option<Amount<D>> {
+        let currency = self.currency.clone()?;
+        let mut value: D = Default::default();
+        if self.number_per.is_none() && self.number_total.is_none() {
+            return None;
+        }
+        if let Some(per) = &self.number_per {
+            value += units.clone() * per.clone();
+        }
+        if let Some(total) = &self.number_total {
+            if *units < 0.into() {
+                value += -total.clone();
+            } else {
+                value += total.clone();
+            }
+        }
+        Some(Amount { value, currency })
+    }
 }
 
 #[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serialize", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(
+    feature = "serialize",
+    serde(bound = "D: Decimal", rename_all = "lowercase")
+)]
 pub enum PostingPrice<D> {
     Unit(Amount<D>),
     Total(Amount<D>),
 }
 
 #[derive(Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serialize", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(
+    feature = "serialize",
+    serde(bound = "D: Decimal", tag = "type", rename_all = "lowercase")
+)]
 pub enum DirectiveContent<D> {
     Balance(Balance<D>),
     Close(Close),
+    #[cfg_attr(feature = "serialize", serde(with = "crate::serialize::commodity"))]
     Commodity(Currency),
     Event(Event),
     Open(Open),
@@ -177,17 +545,31 @@ impl<D> DirectiveContent<D> {
 }
 
 #[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serialize", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serialize", serde(bound = "D: Decimal"))]
 pub struct Price<D> {
     pub currency: Currency,
     pub amount: Amount<D>,
 }
 
 #[derive(Clone, Debug, Hash, PartialEq, Eq)]
+#[cfg_attr(feature = "serialize", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serialize", serde(bound = "D: Decimal"))]
 pub struct Amount<D> {
+    #[cfg_attr(feature = "serialize", serde(with = "crate::serialize::decimal"))]
     pub value: D,
     pub currency: Currency,
 }
 
+impl<D> Amount<D> {
+    pub fn new(value: D, currency: impl Into<Currency>) -> Self {
+        Self {
+            value,
+            currency: currency.into(),
+        }
+    }
+}
+
 impl<D> Amount<D>
 where
     D: Decimal,
@@ -214,7 +596,7 @@ where
     }
 }
 
-impl<'a, D> Neg for &'a Amount<D>
+impl<D> Neg for &Amount<D>
 where
     D: Decimal,
 {
@@ -229,18 +611,31 @@ where
 }
 
 #[derive(Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serialize", derive(serde::Serialize, serde::Deserialize))]
 pub struct Open {
     pub account: Account,
+    #[cfg_attr(
+        feature = "serialize",
+        serde(serialize_with = "crate::serialize::sorted_currencies")
+    )]
     pub currencies: HashSet<Currency>,
+    /// Booking method used for reductions of the account (like "FIFO"), see inventory::Booking.
+    pub booking_method: Option<String>,
 }
 
 #[derive(Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serialize", derive(serde::Serialize, serde::Deserialize))]
 pub struct Close {
     pub account: Account,
 }
 
-#[derive(Debug, Clone, PartialEq, Eq, Hash)]
-pub struct Account(pub String);
+/// An account name, shared so that it is cheap to clone.
+#[derive(Debug, Clone, PartialOrd, Ord, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serialize", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serialize", serde(from = "String", into = "String"))]
+pub struct Account(Symbol);
+
+impl_symbol!(Account);
 
 pub trait Decimal:
     Clone
@@ -286,45 +681,39 @@ impl<D> Transaction<D>
 where
     D: Decimal,
 {
-    pub fn book(&mut self) -> anyhow::Result<()> {
-        let mut bag = Bag::new();
-        let mut postings_no_amount: Vec<&mut Posting<D>> = Vec::new();
-        for posting in &mut self.postings {
-            match posting_amount_to_balance(posting) {
-                Some(amount) => {
-                    bag += amount;
-                }
-                None => postings_no_amount.push(posting),
-            };
-        }
-        anyhow::ensure!(
-            postings_no_amount.len() <= 1,
-            "more than one posting without amount"
-        );
-        let non_zero_amounts = bag
-            .currencies
-            .iter()
-            .filter(|x| *x.1 != 0.into())
-            .collect::<Vec<_>>();
-        anyhow::ensure!(
-            non_zero_amounts.len() <= 1,
-            "more than one currency does not balance to 0"
-        );
+    /// Returns a builder of a transaction directive at given date.
+    pub fn builder(date: chrono::NaiveDate) -> crate::builder::TransactionBuilder<D> {
+        crate::builder::TransactionBuilder::new(date)
+    }
 
-        if postings_no_amount.len() == 1 {
-            if non_zero_amounts.is_empty() {
-                anyhow::bail!("posting with no amount in transaction that is balanced");
-            }
-            postings_no_amount[0].amount = Some(Amount {
-                value: -non_zero_amounts[0].1.clone(),
-                currency: non_zero_amounts[0].0.clone(),
-            });
-            postings_no_amount[0].autocomputed = true;
-        } else if !non_zero_amounts.is_empty() {
-            anyhow::bail!("transaction does not balance to 0");
-        }
+    /// Interpolates the missing amounts of the postings and verifies that the transaction
+    /// balances.
+    ///
+    /// A posting without amount is filled with the amount that balances the transaction. If more
+    /// than one currency does not balance, the posting is split into one posting per currency.
+    /// Such postings are marked as autocomputed and are not written back.
+    ///
+    /// Cost numbers inferred from the other legs (for specs like `{}`) are only used to verify
+    /// that the transaction balances, they are not stored, so that the written transaction stays
+    /// as it was.
+    pub fn book(&mut self) -> crate::Result<(), D> {
+        self.book_with_tolerance(&Default::default())
+    }
+
+    /// Same as book, but uses given tolerance rules (e.g. the ones configured for the ledger)
+    /// instead of the default ones.
+    pub fn book_with_tolerance(&mut self, tolerance: &Tolerance<D>) -> crate::Result<(), D> {
+        self.clear_autocomputed();
+        let interpolation = crate::booking::interpolate(&self.postings, tolerance)?;
+        crate::booking::fill_auto_posting(&mut self.postings, interpolation.auto);
         Ok(())
     }
+
+    /// Reverts the results of the previous booking, so that the transaction can be booked again
+    /// after being modified.
+    pub(crate) fn clear_autocomputed(&mut self) {
+        crate::booking::clear_autocomputed(&mut self.postings);
+    }
 }
 
 pub(crate) fn posting_amount_to_balance<D: Decimal>(posting: &Posting<D>) -> Option<Amount<D>> {
@@ -339,12 +728,8 @@ pub(crate) fn posting_amount_to_balance<D: Decimal>(posting: &Posting<D>) -> Opt
         (-1).into()
     };
     if let Some(cost) = &posting.cost {
-        if let Some(cost_amount) = &cost.amount {
-            return Some(Amount {
-                // TODO: figure out a way to avoid doing clone here.
-                value: amount.value.clone() * cost_amount.value.clone(),
-                currency: cost_amount.currency.clone(),
-            });
+        if let Some(total) = cost.total_cost(&amount.value) {
+            return Some(total);
         }
     }
     match &posting.price {
@@ -364,3 +749,84 @@ pub(crate) fn posting_amount_to_balance<D: Decimal>(posting: &Posting<D>) -> Opt
     }
     Some(amount.clone())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn iterates_directives_in_canonical_order() {
+        let main = crate::parse(
+            r#"2020-01-02 close Assets:Cash
+2020-01-02 * "Second"
+  Assets:Cash -1 CHF
+  Expenses:Food
+2020-01-02 balance Assets:Cash 1 CHF
+"#,
+        )
+        .unwrap();
+        let other = crate::parse(
+            r#"2020-01-02 * "Third"
+  Assets:Cash -1 CHF
+  Expenses:Food
+2020-01-01 open Assets:Cash
+2020-01-02 open Expenses:Food
+"#,
+        )
+        .unwrap();
+        let ledger = Ledger {
+            files: vec![("main".into(), main), ("other".into(), other)],
+        };
+        let order: Vec<(&str, Option<u32>)> = ledger
+            .directives()
+            .map(|(path, d)| (path.to_str().unwrap(), d.line))
+            .collect();
+        assert_eq!(
+            order,
+            vec![
+                ("other", Some(4)),
+                ("other", Some(5)),
+                ("main", Some(5)),
+                ("main", Some(2)),
+                ("other", Some(1)),
+                ("main", Some(1)),
+            ]
+        );
+    }
+
+    #[test]
+    fn shares_accounts_and_currencies() {
+        let a = Account::from(String::from("Assets:Cash"));
+        let b = Account::new("Assets:Cash");
+        assert_eq!(a, b);
+        assert!(std::ptr::eq(a.as_str(), a.clone().as_str()));
+        assert_ne!(a, Account::new("Assets:Bank"));
+        assert!(Currency::new("CHF") < Currency::new("USD"));
+        assert_eq!(format!("{:?}", a), r#"Account("Assets:Cash")"#);
+    }
+
+    #[tokio::test]
+    async fn interns_parsed_accounts_and_currencies() {
+        let read = |path: PathBuf| {
+            std::future::ready(Ok(match path.to_str().unwrap() {
+                "main.beancount" => {
+                    "include \"other.beancount\"\n2020-01-01 open Assets:Cash CHF\n".into()
+                }
+                _ => "2020-01-02 balance Assets:Cash 10 CHF\n".into(),
+            }))
+        };
+        let ledger: Ledger<rust_decimal::Decimal> =
+            Ledger::read("main.beancount", read).await.unwrap();
+        let (open, balance) = match (
+            &ledger.files[0].1.directives[0].content,
+            &ledger.files[1].1.directives[0].content,
+        ) {
+            (DirectiveContent::Open(open), DirectiveContent::Balance(balance)) => (open, balance),
+            _ => panic!("unexpected directives"),
+        };
+        assert!(Arc::ptr_eq(&open.account.0 .0, &balance.account.0 .0));
+        let currency = open.currencies.iter().next().unwrap();
+        assert!(Arc::ptr_eq(&currency.0 .0, &balance.amount.currency.0 .0));
+    }
+}